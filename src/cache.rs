@@ -5,18 +5,22 @@
 //! Build Artifact Cache
 
 use crate::error::{Error, Result};
+use crate::fs::{CopyOptions, LinkMode, Mtime, SpecialFiles, Symlinks};
 use crate::git::{Object, Oid, Repo};
+use crate::pattern::{self, Bindings, Pattern};
+use crate::util::{lock, normalize_path, resolve_path, tmp_suffix};
 use derivative::Derivative;
 use file_lock::{FileLock, FileOptions};
-use log::{debug, error, trace, warn};
-use regex::Regex;
+use log::{debug, error, info, trace, warn};
 use serde::Deserialize;
-use std::cell::RefCell;
-use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{ErrorKind, Write};
+use std::path::{Component, Path, PathBuf};
 use std::string::String;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
 use tuple_transpose::TupleTranspose;
 
 /// A build artifact.
@@ -26,22 +30,26 @@ pub struct Artifact {
     ///
     /// ## Pattern Artifacts
     ///
-    /// If an Artifact name contains exactly one `%`, that artifact is a *Pattern Artifact*.
-    /// Inspired by [GNU Make's Pattern
+    /// If an Artifact name contains wildcards, that artifact is a *Pattern Artifact*.  Inspired by
+    /// [GNU Make's Pattern
     /// Rules](https://www.gnu.org/software/make/manual/html_node/Pattern-Intro.html), a Pattern
     /// Artifact allows one Artifact to match multiple build artifacts with similar input and output
     /// structures.  For this, the actual name given to the [`artifact` method of a
-    /// cache](struct.Cache.html#method.artifact) is matched against the name of the artifact, which
-    /// contains `%` for a Pattern Artifact.  The `%` is treated as a wildcard that matches one or
-    /// multiple characters among alphanumerics (`[[:alnum:]]`), underscore (`_`), dash (`-`), dot
-    /// (`.`), and plus (`+`) non-greedily.  Rationale: Those characters are commonly used in file
-    /// names but not commonly used to separate paths or path components (whereas `:`, `/`, and
-    /// others are).
+    /// cache](struct.Cache.html#method.artifact) is matched against the name of the artifact.  A
+    /// wildcard is either the anonymous `%` or a named wildcard in braces (e.g., `{target}`), and
+    /// each wildcard may occur at most once in a name (e.g., `lib-{target}-{config}`).  Doubled
+    /// braces (`{{` and `}}`) are literal braces in the names and paths of all Artifacts.  Wildcards
+    /// match one or multiple characters among alphanumerics (`[[:alnum:]]`), underscore (`_`), dash
+    /// (`-`), dot (`.`), and plus (`+`) non-greedily.  Rationale: Those characters are commonly used
+    /// in file names but not commonly used to separate paths or path components (whereas `:`, `/`,
+    /// and others are).
     ///
-    /// At most one Pattern Artifact is allowed to match the given name.  If multiple Pattern
-    /// Artifacts would match, the match fails.
+    /// An Artifact whose name matches literally takes precedence over Pattern Artifacts.  If
+    /// multiple Pattern Artifacts match the given name, the most specific one (i.e., the one with
+    /// the most characters outside of wildcards) is used and, among equally specific ones, the one
+    /// defined first.
     ///
-    /// The substring matching the wildcard is substituted for the `%` character in all inputs and
+    /// The substring matching each wildcard is substituted for that wildcard in all inputs and
     /// outputs of the Pattern Artifact.
     pub name: String,
     /// Paths of the Artifact inputs, relative to the root of a repository.  Each path may be a file
@@ -52,11 +60,13 @@ pub struct Artifact {
     /// when none of the inputs changes between two Git objects, the entire Artifact is considered
     /// identical for those two objects.  Any one input may be used in more than one Artifact.
     ///
-    /// If the Artifact is part of a Manifest file loaded with the
-    /// [`from_path`](../config/struct.Manifest.html#method.from_path) function, the Manifest file
-    /// is an implicit input dependency of the Artifact.
+    /// The definition of the Artifact (i.e., its inputs, outputs, `symlinks`, `special_files`, and
+    /// `clean`) is an implicit input dependency of the Artifact: the cache only uses entries that
+    /// were inserted with an identical definition (see
+    /// [`definition_key`](struct.Cache.html#method.definition_key)).  Changes to the definitions of
+    /// other Artifacts do not affect the Artifact.
     ///
-    /// If the Artifact is a Pattern Artifact, each path may contain up to one `%`.
+    /// If the Artifact is a Pattern Artifact, each path may contain the wildcards of its name.
     pub inputs: Vec<PathBuf>,
     /// Paths of the Artifact outputs, relative to the root of a repository.  Each path may be a
     /// file or a directory.
@@ -66,41 +76,449 @@ pub struct Artifact {
     /// required to "use" the artifact but can (and in most cases should) omit intermediate build
     /// products.
     ///
-    /// If the Artifact is a Pattern Artifact, each path may contain up to one `%`.
+    /// If the Artifact is a Pattern Artifact, each path may contain the wildcards of its name.
     pub outputs: Vec<PathBuf>,
+    /// Whether getting the Artifact from the cache removes the files in its outputs that do not
+    /// exist in the cache entry, so that each output mirrors the entry exactly.  This can also be
+    /// requested for a single retrieval with [`clean`](struct.Cache.html#structfield.clean).
+    #[serde(default)]
+    pub clean: bool,
+    /// How special files (FIFOs, sockets, and device nodes) in the outputs of the Artifact are
+    /// handled on insertion and retrieval.  By default, they are an error.
+    #[serde(default)]
+    pub special_files: SpecialFiles,
+    /// How symlinks in the outputs of the Artifact are handled on insertion.  Regardless of this
+    /// policy, symlinks that point outside of the repository are never restored from the cache.
+    #[serde(default)]
+    pub symlinks: Symlinks,
+    /// Valid values of the wildcards of a Pattern Artifact (keyed by wildcard name, `%` for the
+    /// anonymous wildcard).  A wildcard with values only matches those values, and a Pattern
+    /// Artifact with values for all wildcards can be enumerated with
+    /// [`instances`](struct.Cache.html#method.instances).
+    #[serde(default)]
+    pub values: BTreeMap<String, Vec<String>>,
 }
 
 /// Artifacts of a cache.
 pub type Artifacts = Vec<Artifact>;
 
+/// When entries inserted into a tiered cache are written to the tiers behind the primary
+/// directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WritePolicy {
+    /// Write entries to all tiers on insertion.
+    #[default]
+    WriteThrough,
+    /// Write entries only to the primary directory on insertion and to the tiers on
+    /// [`flush`](struct.Cache.html#method.flush).
+    WriteBack,
+}
+
+impl std::fmt::Display for WritePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WritePolicy::WriteThrough => write!(f, "write-through"),
+            WritePolicy::WriteBack => write!(f, "write-back"),
+        }
+    }
+}
+
+impl std::str::FromStr for WritePolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<WritePolicy> {
+        match s {
+            "write-through" => Ok(WritePolicy::WriteThrough),
+            "write-back" => Ok(WritePolicy::WriteBack),
+            _ => Error::result(format!(
+                "Unknown write policy \"{}\" (expected \"write-through\" or \"write-back\")!",
+                s
+            )),
+        }
+    }
+}
+
+/// The modification times of outputs in the cache and in the repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MtimePolicy {
+    /// Outputs get the time at which they are inserted or retrieved.
+    #[default]
+    Touch,
+    /// Outputs keep their modification times on insertion and retrieval.
+    Preserve,
+    /// Outputs keep their modification times on insertion, and retrieved outputs get a
+    /// modification time older than all inputs of the artifact.
+    OlderThanInputs,
+}
+
+impl std::fmt::Display for MtimePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MtimePolicy::Touch => write!(f, "touch"),
+            MtimePolicy::Preserve => write!(f, "preserve"),
+            MtimePolicy::OlderThanInputs => write!(f, "older-than-inputs"),
+        }
+    }
+}
+
+impl std::str::FromStr for MtimePolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<MtimePolicy> {
+        match s {
+            "touch" => Ok(MtimePolicy::Touch),
+            "preserve" => Ok(MtimePolicy::Preserve),
+            "older-than-inputs" => Ok(MtimePolicy::OlderThanInputs),
+            _ => Error::result(format!(
+                "Unknown modification time policy \"{}\" (expected \"touch\", \"preserve\", or \"older-than-inputs\")!",
+                s
+            )),
+        }
+    }
+}
+
+/// How the entries of a cache are keyed.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyMode {
+    /// Entries are stored under the commit that last modified the inputs of an artifact.  An
+    /// entry is used for all descendants of that commit in which the inputs are unchanged.
+    #[default]
+    Commit,
+    /// Entries are stored under a key derived from the Git tree and blob IDs of the inputs of an
+    /// artifact in `HEAD`.  An entry is used whenever the inputs are identical, regardless of
+    /// history (e.g., on unrelated branches).
+    TreeHash,
+}
+
+impl std::fmt::Display for KeyMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyMode::Commit => write!(f, "commit"),
+            KeyMode::TreeHash => write!(f, "tree-hash"),
+        }
+    }
+}
+
+impl std::str::FromStr for KeyMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<KeyMode> {
+        match s {
+            "commit" => Ok(KeyMode::Commit),
+            "tree-hash" => Ok(KeyMode::TreeHash),
+            _ => Error::result(format!(
+                "Unknown key mode \"{}\" (expected \"commit\" or \"tree-hash\")!",
+                s
+            )),
+        }
+    }
+}
+
+/// Which entry is used if several entries in a cache directory match an artifact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SelectionPolicy {
+    /// The entry of the object closest to the required object in the commit graph.
+    #[default]
+    Closest,
+    /// The most recently inserted entry.
+    MostRecent,
+    /// The closest entry whose files match the checksums recorded on insertion.  Entries that do
+    /// not match (or have no checksums) are not used.
+    Verified,
+}
+
+impl std::fmt::Display for SelectionPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectionPolicy::Closest => write!(f, "closest"),
+            SelectionPolicy::MostRecent => write!(f, "most-recent"),
+            SelectionPolicy::Verified => write!(f, "verified"),
+        }
+    }
+}
+
+impl std::str::FromStr for SelectionPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<SelectionPolicy> {
+        match s {
+            "closest" => Ok(SelectionPolicy::Closest),
+            "most-recent" => Ok(SelectionPolicy::MostRecent),
+            "verified" => Ok(SelectionPolicy::Verified),
+            _ => Error::result(format!(
+                "Unknown selection policy \"{}\" (expected \"closest\", \"most-recent\", or \"verified\")!",
+                s
+            )),
+        }
+    }
+}
+
+/// How artifacts are keyed in a shallow clone if the history of their inputs is truncated, so the
+/// commit that last modified the inputs cannot be determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShallowPolicy {
+    /// Key the artifact by the Git IDs of its inputs (as in `KeyMode::TreeHash`).
+    #[default]
+    TreeHash,
+    /// Fetch more history until the commit that last modified the inputs is known, and key the
+    /// artifact by the tree IDs of its inputs only if fetching fails.
+    Deepen,
+}
+
+impl std::fmt::Display for ShallowPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShallowPolicy::TreeHash => write!(f, "tree-hash"),
+            ShallowPolicy::Deepen => write!(f, "deepen"),
+        }
+    }
+}
+
+impl std::str::FromStr for ShallowPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<ShallowPolicy> {
+        match s {
+            "tree-hash" => Ok(ShallowPolicy::TreeHash),
+            "deepen" => Ok(ShallowPolicy::Deepen),
+            _ => Error::result(format!(
+                "Unknown shallow clone policy \"{}\" (expected \"tree-hash\" or \"deepen\")!",
+                s
+            )),
+        }
+    }
+}
+
+/// Name of the file in each entry that records the checksums of the files in the entry.
+const CHECKSUMS_FILE: &str = ".memora-checksums";
+
+/// Name of the file in each entry that records when the entry was inserted, in seconds since the
+/// Unix epoch.  Entries are copied between cache directories with new modification times, so the
+/// time of insertion is recorded explicitly.
+const INSERTED_FILE: &str = ".memora-inserted";
+
+/// Write a new entry at `path` by calling `write` with a temporary directory next to `path`, which
+/// is renamed to `path` once `write` succeeds.  Thus, an interrupted write (e.g., by a crash or a
+/// full disk) never leaves a partial entry.  The caller must hold a read-write lock on the store.
+fn write_entry<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&Path) -> Result<()>,
+{
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy(),
+        None => return Error::result(format!("Invalid entry path {:?}!", path)),
+    };
+    let tmp = path.with_file_name(format!(".{}.{}", name, tmp_suffix()));
+    let remove_tmp = || match fs::remove_dir_all(&tmp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(Error::chain(format!("Could not remove {:?}:", tmp), e))
+        }
+        _ => Ok(()),
+    };
+    // Remove the remains of an interrupted write.
+    remove_tmp()?;
+    let result = write(&tmp).and_then(|_| {
+        trace!("Renaming {:?} to {:?}.", tmp, path);
+        fs::rename(&tmp, path)
+            .map_err(|cause| Error::chain(format!("Could not rename {:?}:", tmp), cause))
+    });
+    if result.is_err() {
+        if let Err(e) = remove_tmp() {
+            warn!("{}", e);
+        }
+    }
+    result
+}
+
+/// The lock of a store (i.e., the primary directory or a tier of a cache) within this process.
+///
+/// Lock files are locked with `fcntl`, whose locks are owned by the process rather than the
+/// thread: threads of one process never block each other, and closing any file descriptor of the
+/// lock file releases the locks of all threads.  Thus, threads first lock the store within the
+/// process, and the lock file is locked by the first holder and unlocked by the last.
+#[derive(Default)]
+struct StoreLock {
+    state: Mutex<StoreLockState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct StoreLockState {
+    /// Number of threads holding the lock.
+    holders: usize,
+    /// Whether the lock is held read-only (and can thus be shared by further readers).
+    read_only: bool,
+    /// The lock on the lock file, held while there are holders.
+    file: Option<FileLock>,
+}
+
+impl StoreLock {
+    /// The lock of the store with lock file `path`.  All caches in the process share the lock.
+    fn get(path: &Path) -> Arc<StoreLock> {
+        static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<StoreLock>>>> = OnceLock::new();
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        lock(LOCKS.get_or_init(Default::default))
+            .entry(path)
+            .or_default()
+            .clone()
+    }
+}
+
+/// A lock on a store, which is released when dropped.
+struct StoreLockGuard(Arc<StoreLock>);
+
+impl Drop for StoreLockGuard {
+    fn drop(&mut self) {
+        let mut state = lock(&self.0.state);
+        state.holders -= 1;
+        if state.holders == 0 {
+            state.file = None;
+            self.0.released.notify_all();
+        }
+    }
+}
+
+/// A directory of a cache behind its primary directory.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Tier {
+    /// Path of the directory.
+    pub path: PathBuf,
+    /// Whether the directory is only searched and never written to.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+impl std::fmt::Display for Tier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.path)?;
+        if self.read_only {
+            write!(f, " (read-only)")?;
+        }
+        Ok(())
+    }
+}
+
 /// A build artifact cache.
+///
+/// A cache stores its entries in a primary directory (`path`), optionally backed by a chain of
+/// slower directories (`tiers`, e.g., a cache shared over the network).  Lookups search the primary
+/// directory first and then each tier in order; an entry found in a tier is copied to the primary
+/// directory.  Insertions write to the primary directory and, depending on the `write_policy`, to
+/// each tier that is not read-only.
 #[derive(Derivative)]
 #[derivative(Debug)]
-pub struct Cache<'a> {
+pub struct Cache {
     pub path: PathBuf,
-    pub repo: &'a Repo,
-    artifacts: &'a Artifacts, // TODO: make Artifacts owned?
+    pub repo: Repo,
+    /// Maximum duration to wait for the lock of the cache.  If `None`, wait indefinitely.
+    pub lock_timeout: Option<Duration>,
+    /// Directories behind the primary directory, in order of lookup.
+    pub tiers: Vec<Tier>,
+    /// When inserted entries are written to the `tiers`.
+    pub write_policy: WritePolicy,
+    /// How outputs are transferred from the cache to the repository.
+    pub link_mode: LinkMode,
+    /// Whether getting an artifact removes the files in its outputs that do not exist in the cache
+    /// entry, for all artifacts (in addition to those whose `clean` flag is set).
+    pub clean: bool,
+    /// The modification times of inserted and retrieved outputs.
+    pub mtime_policy: MtimePolicy,
+    /// Whether the owner and group of outputs are preserved on insertion and retrieval.
+    pub preserve_ownership: bool,
+    /// Whether the extended attributes of outputs are preserved on insertion and retrieval.
+    pub preserve_xattrs: bool,
+    /// Number of threads that copy the files of outputs.  If zero, as many threads as the machine
+    /// can run in parallel are used.
+    pub copy_threads: usize,
+    /// How entries are keyed.  In [`TreeHash`](enum.KeyMode.html#variant.TreeHash) mode, entries
+    /// are stored under `tree/<key>` in each directory, and the `oid` of the Objects returned for
+    /// them is the key of the inputs.
+    pub key_mode: KeyMode,
+    /// Whether entries of objects that are not descendants of the required object (e.g., on
+    /// sibling branches) are used if the inputs of the artifact are identical.  Of several such
+    /// entries, one is selected according to the `selection_policy`.
+    pub cross_branch_hits: bool,
+    /// Which entry is used if several entries in a directory match an artifact.
+    pub selection_policy: SelectionPolicy,
+    /// How artifacts whose history is truncated by a shallow clone are keyed if `key_mode` is
+    /// `KeyMode::Commit`.
+    pub shallow_policy: ShallowPolicy,
+    artifacts: Artifacts,
+    /// The parsed name of each artifact, or `None` for artifacts that are not Pattern Artifacts.
     #[derivative(Debug = "ignore")]
-    objects_path_identity_cache: RefCell<HashMap<(Oid, Oid, PathBuf), bool>>,
+    patterns: Vec<Option<Pattern>>,
+    #[derivative(Debug = "ignore")]
+    definition_key_cache: Mutex<HashMap<String, Oid>>,
+    #[derivative(Debug = "ignore")]
+    key_mode_cache: Mutex<HashMap<String, KeyMode>>,
 }
 
-impl<'a> Cache<'a> {
-    pub fn new(path: PathBuf, repo: &'a Repo, artifacts: &'a Artifacts) -> Cache<'a> {
+impl Cache {
+    pub fn new(path: PathBuf, repo: &Repo, artifacts: &Artifacts) -> Cache {
+        let patterns: Vec<_> = artifacts.iter().map(|a| Pattern::parse(&a.name)).collect();
+        // Artifacts that are not Pattern Artifacts are used as defined, except for escaped braces.
+        let artifacts = artifacts
+            .iter()
+            .zip(&patterns)
+            .map(|(arti, pattern)| match pattern {
+                None => Cache::instantiate(arti, &[]).unwrap_or_else(|_| arti.clone()),
+                Some(_) => arti.clone(),
+            })
+            .collect();
         Cache {
             path,
-            repo,
+            repo: repo.clone(),
+            lock_timeout: None,
+            tiers: vec![],
+            write_policy: WritePolicy::default(),
+            link_mode: LinkMode::default(),
+            clean: false,
+            mtime_policy: MtimePolicy::default(),
+            preserve_ownership: false,
+            preserve_xattrs: false,
+            copy_threads: 1,
+            key_mode: KeyMode::default(),
+            cross_branch_hits: false,
+            selection_policy: SelectionPolicy::default(),
+            shallow_policy: ShallowPolicy::default(),
             artifacts,
-            objects_path_identity_cache: RefCell::new(HashMap::new()),
+            patterns,
+            definition_key_cache: Mutex::new(HashMap::new()),
+            key_mode_cache: Mutex::new(HashMap::new()),
         }
     }
 
-    fn lock_file_path(&self) -> PathBuf {
-        self.path.as_path().join(".lock")
+    fn lock_file_path(root: &Path) -> PathBuf {
+        root.join(".lock")
+    }
+
+    /// Path of the file listing the entries that have not been written to the tiers yet.
+    fn pending_file_path(&self) -> PathBuf {
+        self.path.join(".pending")
+    }
+
+    /// The primary directory followed by the tiers.
+    fn stores(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.path.as_path()).chain(
+            self.tiers
+                .iter()
+                .map(|t| t.path.as_path())
+                .filter(move |p| *p != self.path),
+        )
+    }
+
+    /// The tiers that are not read-only.
+    fn writable_tiers(&self) -> impl Iterator<Item = &Path> {
+        self.tiers
+            .iter()
+            .filter(move |t| !t.read_only && t.path != self.path)
+            .map(|t| t.path.as_path())
+    }
+
+    fn is_read_only(&self, root: &Path) -> bool {
+        self.tiers.iter().any(|t| t.read_only && t.path == root)
     }
 
-    fn lock(&self, read_only: bool) -> Result<FileLock> {
+    fn lock(&self, root: &Path, read_only: bool) -> Result<StoreLockGuard> {
         let path = {
-            let path = self.lock_file_path();
+            let path = Cache::lock_file_path(root);
             if !path.is_file() {
                 debug!("Creating lock file {:?}.", path);
                 fs::File::create(&path).map_err(|cause| {
@@ -110,130 +528,442 @@ impl<'a> Cache<'a> {
             match path.to_str() {
                 None => Error::result(format!(
                     "Could not stringify path to lock file {:?}",
-                    self.lock_file_path()
+                    Cache::lock_file_path(root)
                 )),
                 Some(s) => Ok(String::from(s)),
             }
         }?;
-        debug!("Obtaining lock ..");
-        let lock = FileLock::lock(
-            &path,
-            true,
-            FileOptions::new().read(read_only).write(!read_only),
-        )
-        .map_err(|cause| Error::chain(format!("Could not lock {:?}!", path), cause))?;
+        debug!("Obtaining lock on {:?} ..", root);
+        let start = Instant::now();
+        let timed_out = || match self.lock_timeout {
+            Some(timeout) => start.elapsed() >= timeout,
+            None => false,
+        };
+        let store = StoreLock::get(Path::new(&path));
+        let mut state = lock(&store.state);
+        // Wait for the threads of this process that hold a conflicting lock.
+        while state.holders > 0 && !(read_only && state.read_only) {
+            if timed_out() {
+                return Error::result(format!(
+                    "Could not lock {:?}: held by another thread!",
+                    path
+                ));
+            }
+            trace!("Lock is held by another thread, waiting.");
+            state = store
+                .released
+                .wait_timeout(state, Duration::from_millis(100))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        if state.holders == 0 {
+            let options = || FileOptions::new().read(read_only).write(!read_only);
+            let file = match self.lock_timeout {
+                None => FileLock::lock(&path, true, options()),
+                Some(_) => {
+                    // Poll a non-blocking lock until it is obtained or the timeout expires.
+                    loop {
+                        match FileLock::lock(&path, false, options()) {
+                            Err(e) if e.kind() == ErrorKind::WouldBlock && !timed_out() => {
+                                trace!("Lock is held by another process, retrying.");
+                                std::thread::sleep(Duration::from_millis(100));
+                            }
+                            result => break result,
+                        }
+                    }
+                }
+            }
+            .map_err(|cause| Error::chain(format!("Could not lock {:?}!", path), cause))?;
+            state.file = Some(file);
+            state.read_only = read_only;
+        }
+        state.holders += 1;
+        drop(state);
         if read_only {
             debug!("Read-only lock obtained.");
         } else {
             debug!("Read-write lock obtained.");
         }
-        Ok(lock)
+        Ok(StoreLockGuard(store))
+    }
+
+    fn lock_read_only(&self, root: &Path) -> Result<StoreLockGuard> {
+        self.lock(root, true)
     }
 
-    fn lock_read_only(&self) -> Result<FileLock> {
-        self.lock(true)
+    fn lock_read_write(&self, root: &Path) -> Result<StoreLockGuard> {
+        self.lock(root, false)
     }
 
-    fn lock_read_write(&self) -> Result<FileLock> {
-        self.lock(false)
+    /// Obtain a read-only lock on a tier.  A read-only tier without lock file is not locked, since
+    /// the lock file cannot be created.
+    fn lock_tier_read_only(&self, root: &Path) -> Result<Option<StoreLockGuard>> {
+        if self.is_read_only(root) && !Cache::lock_file_path(root).is_file() {
+            debug!("Not locking read-only cache {:?} without lock file.", root);
+            return Ok(None);
+        }
+        self.lock_read_only(root).map(Some)
     }
 
     /// Get an artifact definition by name.
+    ///
+    /// The returned Artifact is [validated](#method.validate_artifact).
     pub fn artifact(&self, name: &str) -> Result<Artifact> {
+        let artifact = self.find_artifact(name)?;
+        self.validate_artifact(&artifact)?;
+        Ok(artifact)
+    }
+
+    /// Ensure that an Artifact cannot address anything outside of its directory in the cache or
+    /// outside of the repository.  The name must be a relative path without `.` and `..`
+    /// components, so that it stays within the `<oid>` directory of a cache entry.  Inputs and
+    /// outputs must neither leave the repository (or the `<oid>/<artifact>` directory of a cache
+    /// entry) through `..` components, nor through symlinks in the repository.
+    ///
+    /// Since the `%` wildcard of Pattern Artifacts can match `..`, this must be checked after
+    /// pattern substitution.
+    pub fn validate_artifact(&self, artifact: &Artifact) -> Result<()> {
+        if artifact.name.is_empty()
+            || Path::new(&artifact.name)
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Error::result(format!(
+                "Artifact name \"{}\" is empty or contains `.`, `..`, or a leading `/`!",
+                artifact.name
+            ));
+        }
+        if let Some(path) = artifact
+            .outputs
+            .iter()
+            .find(|p| resolve_path(p) == Path::new(""))
+        {
+            return Error::result(format!(
+                "Output {:?} of artifact \"{}\" is the root of the repository!",
+                path, artifact.name
+            ));
+        }
+        for path in artifact.inputs.iter().chain(&artifact.outputs) {
+            crate::fs::check_confined(self.repo.path(), path).map_err(|cause| {
+                Error::chain(
+                    format!("Invalid path of artifact \"{}\":", artifact.name),
+                    cause,
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    fn find_artifact(&self, name: &str) -> Result<Artifact> {
         // Match artifact names directly.
-        match self.artifacts.iter().find(|arti| arti.name == name) {
-            Some(a) => Ok(a.clone()), // Literal match
-            None => {
-                // No literal match => try pattern matches.
-                // Pattern artifacts are those containing exactly one `%` in their name.
-                let pattern_artifacts = self
-                    .artifacts
-                    .iter()
-                    .filter(|arti| arti.name.matches('%').count() == 1);
-                // Replace the `%` character by the defined regex (see above) and match the given
-                // `name`.
-                let mut matching_captures = pattern_artifacts
-                    .filter_map(|arti| {
-                        let pattern = format!(
-                            "^{}$",
-                            regex::escape(&arti.name).replace('%', r"([[[:alnum:]]_\-.+]+?)")
-                        );
-                        match Regex::new(&pattern) {
-                            Ok(re) => re.captures(name).map(|c| (c, arti)),
-                            Err(_) => None,
-                        }
-                    })
-                    .inspect(|p| trace!("{:?}", p));
+        if let Some(arti) = self.artifacts.iter().find(|arti| arti.name == name) {
+            return Ok(arti.clone());
+        }
+        // No literal match => try pattern matches.
+        match self.find_pattern_artifact(name) {
+            None => Error::result(format!("Artifact \"{}\" is not defined!", name)),
+            Some((arti, bindings)) => Cache::instantiate(arti, &bindings),
+        }
+    }
 
-                /// Substitute the first `%` placeholder in `path` with `actual`.
-                fn subst_placeholder(path: &Path, actual: &str) -> Result<PathBuf> {
-                    match path.to_str() {
-                        None => {
-                            Error::result(format!("Could not convert path {:?} to string!", path))
-                        }
-                        Some(s) => Ok(PathBuf::from(s.replacen('%', actual, 1))),
+    /// Find the Pattern Artifact that matches `name` and the values of its wildcards.  Of all
+    /// matching Pattern Artifacts, this is the most specific one (see
+    /// [`Pattern::specificity`](../pattern/struct.Pattern.html#method.specificity)) and, among
+    /// equally specific ones, the one defined first.
+    fn find_pattern_artifact(&self, name: &str) -> Option<(&Artifact, Bindings)> {
+        let patterns = self.artifacts.iter().zip(&self.patterns);
+        let mut matches = patterns.filter_map(|(arti, pattern)| {
+            let pattern = pattern.as_ref()?;
+            let bindings = pattern.matches(name)?;
+            let allowed = bindings.iter().all(|(wildcard, value)| {
+                arti.values
+                    .get(wildcard)
+                    .map_or(true, |values| values.contains(value))
+            });
+            match allowed {
+                true => Some((pattern.specificity(), arti, bindings)),
+                false => None,
+            }
+        });
+        let first = matches.next()?;
+        let mut n_matches = 1;
+        let (_, arti, bindings) = matches.fold(first, |best, m| {
+            n_matches += 1;
+            match m.0 > best.0 {
+                true => m,
+                false => best,
+            }
+        });
+        if n_matches > 1 {
+            debug!(
+                "{} pattern artifacts match \"{}\"; using the most specific one, \"{}\".",
+                n_matches, name, arti.name
+            );
+        }
+        Some((arti, bindings))
+    }
+
+    /// Substitute the values of wildcards in `bindings` into the name, inputs, and outputs of
+    /// Pattern Artifact `arti`, and unescape their braces.
+    fn instantiate(arti: &Artifact, bindings: &[(String, String)]) -> Result<Artifact> {
+        let replace_pattern = |paths: &[PathBuf]| -> Result<Vec<PathBuf>> {
+            paths
+                .iter()
+                .map(|path| match path.to_str() {
+                    None => Error::result(format!("Could not convert path {:?} to string!", path)),
+                    Some(s) => Ok(PathBuf::from(pattern::instantiate(s, bindings))),
+                })
+                .collect()
+        };
+        (
+            replace_pattern(&arti.inputs),
+            replace_pattern(&arti.outputs),
+        )
+            .transpose()
+            .map(|(i, o)| Artifact {
+                name: pattern::instantiate(&arti.name, bindings),
+                inputs: i,
+                outputs: o,
+                values: BTreeMap::new(),
+                ..arti.clone()
+            })
+    }
+
+    /// All Artifacts that can be enumerated: each Artifact that is not a Pattern Artifact and each
+    /// instantiation of a Pattern Artifact.  The values of a wildcard are its
+    /// [`values`](struct.Artifact.html#structfield.values) if given and are otherwise
+    /// [discovered](#method.discover) from the files tracked by Git.  Instantiations that match a
+    /// literal Artifact or a more specific Pattern Artifact are omitted, so each returned Artifact
+    /// is what [`artifact`](#method.artifact) returns for its name.
+    pub fn instances(&self) -> Result<Artifacts> {
+        let mut instances = Vec::new();
+        for (arti, pattern) in self.artifacts.iter().zip(&self.patterns) {
+            let pattern = match pattern {
+                None => {
+                    instances.push(arti.clone());
+                    continue;
+                }
+                Some(p) => p,
+            };
+            let (given, discovered): (Vec<String>, Vec<String>) = pattern
+                .wildcards()
+                .iter()
+                .cloned()
+                .partition(|w| arti.values.contains_key(w));
+            let mut all_bindings = Vec::new();
+            for given_bindings in pattern::combinations(&given, &arti.values).unwrap_or_default() {
+                match self.discover(arti, &given_bindings, &discovered) {
+                    None => {
+                        debug!(
+                            "Cannot enumerate pattern artifact \"{}\": no input contains all \
+                             wildcards without values.",
+                            arti.name
+                        );
+                        break;
                     }
+                    Some(found) => all_bindings.extend(found.into_iter().map(|found| {
+                        // Order the values like the wildcards in the name.
+                        pattern
+                            .wildcards()
+                            .iter()
+                            .filter_map(|w| {
+                                given_bindings
+                                    .iter()
+                                    .chain(found.iter())
+                                    .find(|(n, _)| n == w)
+                                    .cloned()
+                            })
+                            .collect::<Bindings>()
+                    })),
                 }
-                // Currently, matching is only successful if there is exactly one match.
-                let capture = matching_captures.next();
-                match capture {
-                    // No pattern matches.
-                    None => Error::result(format!("Artifact \"{}\" is not defined!", name)),
-                    // At least one pattern matches.
-                    Some((capture, arti)) => {
-                        match matching_captures.count() {
-                            0 => {
-                                // Exactly one pattern matches.
-                                let actual = capture.get(1).unwrap().as_str();
-                                let replace_pattern = |paths: &[PathBuf]| -> Result<Vec<PathBuf>> {
-                                    // Unwrap string that matched the `%` placeholder from
-                                    // capture[1]. We may unwrap because we have ensured that the
-                                    // capture contains group 1.
-                                    paths
-                                        .iter()
-                                        .map(|path| subst_placeholder(path, actual))
-                                        .collect()
-                                };
-                                (
-                                    replace_pattern(&arti.inputs),
-                                    replace_pattern(&arti.outputs),
-                                )
-                                    .transpose()
-                                    .map(|(i, o)| Artifact {
-                                        name: name.replacen('%', actual, 1),
-                                        inputs: i,
-                                        outputs: o,
-                                    })
-                            }
-                            _ => Error::result(format!(
-                                "Multiple pattern artifacts match \"{}\"!",
-                                name
-                            )),
-                        }
+            }
+            for bindings in all_bindings {
+                let name = pattern::instantiate(&arti.name, &bindings);
+                if self.artifacts.iter().any(|a| a.name == name) {
+                    continue;
+                }
+                match self.find_pattern_artifact(&name) {
+                    Some((a, b)) if std::ptr::eq(a, arti) && b == bindings => {
+                        instances.push(Cache::instantiate(arti, &bindings)?)
                     }
+                    _ => (),
+                }
+            }
+        }
+        Ok(instances)
+    }
+
+    /// Discover the values of the `wildcards` of Pattern Artifact `arti` from the files tracked by
+    /// Git, after substituting `bindings`.  The first input that contains all `wildcards` (each
+    /// only once) is matched against the paths of tracked files and their parent directories.  For
+    /// example, the values of `%` for input `libs/%` are the names of the files and directories in
+    /// `libs/`.  Returns `None` if no input contains all `wildcards`.
+    fn discover(
+        &self,
+        arti: &Artifact,
+        bindings: &[(String, String)],
+        wildcards: &[String],
+    ) -> Option<Vec<Bindings>> {
+        if wildcards.is_empty() {
+            return Some(vec![Bindings::new()]);
+        }
+        let input = arti.inputs.iter().find_map(|inp| {
+            let inp = pattern::substitute(inp.to_str()?, bindings);
+            let pattern = Pattern::parse(&inp)?;
+            match wildcards.iter().all(|w| pattern.wildcards().contains(w)) {
+                true => Some((PathBuf::from(inp), pattern)),
+                false => None,
+            }
+        })?;
+        let (input, pattern) = input;
+        // Only list the files below the directory that precedes the first wildcard.
+        let dir: PathBuf = input
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .take_while(|c| pattern::wildcards(c).is_empty())
+            .map(|c| pattern::instantiate(&c, &[]))
+            .collect();
+        let depth = input.components().count();
+        let mut discovered = Vec::new();
+        for file in self.repo.tracked_files(&dir) {
+            if file.components().count() < depth {
+                continue;
+            }
+            let prefix: PathBuf = file.components().take(depth).collect();
+            if let Some(found) = prefix.to_str().and_then(|p| pattern.matches(p)) {
+                if !discovered.contains(&found) {
+                    discovered.push(found);
                 }
             }
         }
+        Some(discovered)
     }
 
-    fn objects(&self) -> HashSet<Object<'a>> {
-        let obj_regex = Regex::new("^[[:xdigit:]]{40}$").unwrap();
+    /// Objects for which the store at `root` contains entries.
+    fn objects(&self, root: &Path) -> HashSet<Object> {
         let mut objs = HashSet::new();
-        for entry in fs::read_dir(&self.path).unwrap() {
+        let entries = match fs::read_dir(root) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Could not read cache directory {:?}: {}", root, e);
+                return objs;
+            }
+        };
+        for entry in entries {
             let entry = entry.unwrap();
             let path = entry.path();
             if path.is_dir() {
                 let dirname = path.file_name().unwrap().to_str().unwrap();
-                if obj_regex.is_match(dirname) {
-                    objs.insert(Object::new(dirname.to_string(), &self.repo));
+                // Entries of repositories with another object format cannot match.
+                match Oid::parse(dirname) {
+                    Ok(oid) if oid.format() == self.repo.object_format() => {
+                        objs.insert(Object::new(oid, &self.repo));
+                    }
+                    _ => {}
                 }
             }
         }
         objs
     }
 
-    fn object_artifact_path(&self, object: &Object, artifact: &Artifact) -> PathBuf {
-        self.path.join(&object.oid).join(&artifact.name)
+    /// Determine the key of the definition of an artifact.
+    ///
+    /// The key is the Git blob ID of a normalized description of the inputs and outputs of the
+    /// artifact and of its settings that affect what an entry contains or how it is restored
+    /// (`symlinks`, `special_files`, and `clean`).  Entries of an artifact are stored under the key
+    /// of its definition, so changing the definition of an artifact does not affect the entries of
+    /// other artifacts.
+    pub fn definition_key(&self, artifact: &Artifact) -> Result<Oid> {
+        let definition = {
+            let normalized = |paths: &[PathBuf]| -> String {
+                let mut paths: Vec<String> = paths
+                    .iter()
+                    .map(|p| normalize_path(p).to_string_lossy().into_owned())
+                    .collect();
+                paths.sort();
+                paths.dedup();
+                paths.iter().map(|p| format!("{}\n", p)).collect()
+            };
+            format!(
+                "memora artifact definition 2\ninputs\n{}outputs\n{}\
+                 symlinks {:?}\nspecial_files {:?}\nclean {}\n",
+                normalized(&artifact.inputs),
+                normalized(&artifact.outputs),
+                artifact.symlinks,
+                artifact.special_files,
+                artifact.clean
+            )
+        };
+        if let Some(key) = lock(&self.definition_key_cache).get(&definition) {
+            return Ok(key.clone());
+        }
+        let key = match self.repo.hash_object(&definition) {
+            None => Error::result(format!(
+                "Could not determine definition key of artifact \"{}\"!",
+                artifact.name
+            )),
+            Some(k) => Ok(k),
+        }?;
+        trace!("Definition key of artifact \"{}\": {}", artifact.name, key);
+        lock(&self.definition_key_cache).insert(definition, key.clone());
+        Ok(key)
+    }
+
+    /// How the entries of `artifact` are keyed.  This is `key_mode`, except for artifacts whose
+    /// inputs were last modified before the history of a shallow clone begins: unless the
+    /// `shallow_policy` fetches enough history, these are keyed by the Git IDs of their inputs.
+    fn artifact_key_mode(&self, artifact: &Artifact) -> KeyMode {
+        if self.key_mode == KeyMode::TreeHash || !self.repo.is_shallow() {
+            return self.key_mode;
+        }
+        if let Some(mode) = lock(&self.key_mode_cache).get(&artifact.name) {
+            return *mode;
+        }
+        let truncated = || {
+            let boundary = self.repo.shallow_boundary();
+            artifact.inputs.iter().any(|inp| {
+                self.repo
+                    .last_commit_on_path(inp)
+                    .is_some_and(|commit| boundary.contains(&commit.oid))
+            })
+        };
+        let mut mode = KeyMode::Commit;
+        while truncated() {
+            if self.shallow_policy == ShallowPolicy::Deepen && self.repo.deepen() {
+                continue;
+            }
+            warn!(
+                "The inputs of artifact \"{}\" were last modified before the history of this \
+                 shallow clone begins, so it is keyed by the Git IDs of its inputs instead of a \
+                 commit.  Its entries are shared only with other shallow clones and with the \
+                 `tree-hash` key mode.",
+                artifact.name
+            );
+            mode = KeyMode::TreeHash;
+            break;
+        }
+        lock(&self.key_mode_cache).insert(artifact.name.clone(), mode);
+        mode
+    }
+
+    /// Path of the entry of an artifact for an object, relative to the root of a store.
+    fn entry_path(&self, object: &Object, artifact: &Artifact) -> Result<PathBuf> {
+        let key_dir = match self.artifact_key_mode(artifact) {
+            KeyMode::Commit => PathBuf::from(object.oid.as_str()),
+            KeyMode::TreeHash => Path::new("tree").join(object.oid.as_str()),
+        };
+        Ok(key_dir
+            .join(&artifact.name)
+            .join(self.definition_key(artifact)?.as_str()))
+    }
+
+    fn object_artifact_path(
+        &self,
+        root: &Path,
+        object: &Object,
+        artifact: &Artifact,
+    ) -> Result<PathBuf> {
+        Ok(root.join(self.entry_path(object, artifact)?))
     }
 
     /// Determine whether a subpath exists for an object.
@@ -243,7 +973,24 @@ impl<'a> Cache<'a> {
         artifact: &Artifact,
         subpath: &Path,
     ) -> Option<PathBuf> {
-        let abspath = self.object_artifact_path(object, artifact).join(subpath);
+        self.subpath_in_store(&self.path, object, artifact, subpath)
+    }
+
+    /// Determine whether a subpath exists for an object in the store at `root`.
+    fn subpath_in_store(
+        &self,
+        root: &Path,
+        object: &Object,
+        artifact: &Artifact,
+        subpath: &Path,
+    ) -> Option<PathBuf> {
+        let abspath = match self.object_artifact_path(root, object, artifact) {
+            Ok(p) => p.join(subpath),
+            Err(e) => {
+                warn!("{}", e);
+                return None;
+            }
+        };
         if abspath.exists() {
             Some(abspath)
         } else {
@@ -252,11 +999,14 @@ impl<'a> Cache<'a> {
     }
 
     /// Determine required object for artifact.
+    ///
+    /// In [`TreeHash`](enum.KeyMode.html#variant.TreeHash) mode, this is the key of the inputs
+    /// (see [`Repo::tree_key`](../git/struct.Repo.html#method.tree_key)).
     pub fn required_object(
         &self,
-        artifact: &'a Artifact,
+        artifact: &Artifact,
         ignore_uncommitted_changes: bool,
-    ) -> Option<Object<'a>> {
+    ) -> Option<Object> {
         if !ignore_uncommitted_changes {
             debug!("Checking if any input has uncommitted changes:");
             let has_uncommitted_changes: bool = artifact.inputs.iter().any(|path| {
@@ -272,6 +1022,21 @@ impl<'a> Cache<'a> {
                 debug!("No uncommitted changes found.")
             }
         }
+        if self.artifact_key_mode(artifact) == KeyMode::TreeHash {
+            return match self.repo.tree_key(&artifact.inputs) {
+                Some(key) => {
+                    debug!("Key of inputs: {}.", key);
+                    Some(Object::new(key, &self.repo))
+                }
+                None => {
+                    warn!(
+                        "Could not determine the key of the inputs of artifact \"{}\"!",
+                        artifact.name
+                    );
+                    None
+                }
+            };
+        }
         debug!("Determining last object for each input:");
         let commits: Option<HashSet<Object>> = artifact
             .inputs
@@ -293,35 +1058,57 @@ impl<'a> Cache<'a> {
         let req_obj = self
             .repo
             .oldest_common_descendant_on_current_branch(&commits);
-        if req_obj.is_ok() {
-            debug!("Required object: {:?}.", req_obj);
-            // FIXME: Is the lifetime of Repo for Object declared wrong?  We should be able to
-            // return (a clone of) `req_obj` without the following two lines ..
-            let obj = req_obj.unwrap();
-            Some(Object::new(obj.oid.clone(), self.repo))
-        } else {
-            error!(
-                "Could not determine required object for artifact {:#?}: {:?}",
-                artifact, req_obj
-            );
-            None
+        match req_obj {
+            Ok(obj) => {
+                debug!("Required object: {:?}.", obj);
+                Some(obj)
+            }
+            Err(e) => {
+                error!(
+                    "Could not determine required object for artifact {:#?}: {:?}",
+                    artifact, e
+                );
+                None
+            }
         }
     }
 
     /// Find cached object for artifact.
+    ///
+    /// The primary directory is searched first, then each tier in order.  Each directory is locked
+    /// read-only while it is searched, like in [`get`](#method.get).
     pub fn cached_object(
         &self,
-        artifact: &'a Artifact,
+        artifact: &Artifact,
         ignore_uncommitted_changes: bool,
-    ) -> Option<Object<'a>> {
-        let req_obj = self.required_object(artifact, ignore_uncommitted_changes);
-        if req_obj.is_none() {
-            return None;
+    ) -> Option<Object> {
+        let ancestor = self.required_object(artifact, ignore_uncommitted_changes)?;
+        self.stores()
+            .find_map(|root| self.cached_object_in_tier(root, artifact, &ancestor))
+    }
+
+    /// Find cached object for artifact in the store at `root`, given the required object.
+    fn cached_object_in(
+        &self,
+        root: &Path,
+        artifact: &Artifact,
+        ancestor: &Object,
+    ) -> Option<Object> {
+        debug!("Searching cache {:?}.", root);
+        if self.artifact_key_mode(artifact) == KeyMode::TreeHash {
+            // Entries are stored under the key of the inputs, so there is only one candidate.
+            let complete = artifact.outputs.iter().all(|oup| {
+                self.subpath_in_store(root, ancestor, artifact, oup)
+                    .is_some()
+            });
+            return match complete && !artifact.outputs.is_empty() {
+                true => self.select(root, artifact, ancestor, vec![ancestor.clone()]),
+                false => None,
+            };
         }
-        let ancestor = req_obj.unwrap();
         let mut oup_iter = artifact.outputs.iter();
         // Closure to determine candidates for an output of `artifact`.
-        let oup_candidates = |oup| self.find_candidates(ancestor.clone(), oup, &artifact);
+        let oup_candidates = |oup| self.find_candidates(root, ancestor.clone(), oup, artifact);
         // Compute the initial set for the reduction from the candidates of the first output.
         let initial_candidates = match oup_iter.next() {
             Some(oup) => oup_candidates(oup),
@@ -343,47 +1130,298 @@ impl<'a> Cache<'a> {
             "Intersection of cache candidates: {:?}, selecting one of them.",
             intersection
         );
-        // Pick the first object from the (unordered) intersection set.
-        intersection.and_then(|set| set.iter().next().map(|obj| obj.clone()))
+        intersection
+            .and_then(|set| self.select(root, artifact, ancestor, set.into_iter().collect()))
+    }
+
+    /// Select one of the `candidates` in the store at `root` according to the `selection_policy`
+    /// and log the reason for the selection.  Candidates are ranked deterministically, with ties
+    /// broken by object ID.
+    fn select(
+        &self,
+        root: &Path,
+        artifact: &Artifact,
+        ancestor: &Object,
+        candidates: Vec<Object>,
+    ) -> Option<Object> {
+        let n_candidates = candidates.len();
+        let policy = self.selection_policy;
+        let mut ranked: Vec<(Object, Option<usize>, Option<SystemTime>)> = candidates
+            .into_iter()
+            .map(|obj| match policy {
+                SelectionPolicy::Closest | SelectionPolicy::Verified => {
+                    let distance = match obj == *ancestor {
+                        true => Some(0),
+                        false => obj.distance_to(ancestor),
+                    };
+                    (obj, distance, None)
+                }
+                SelectionPolicy::MostRecent => {
+                    let inserted = self
+                        .object_artifact_path(root, &obj, artifact)
+                        .ok()
+                        .and_then(|path| Cache::insertion_time(&path));
+                    (obj, None, inserted)
+                }
+            })
+            .collect();
+        // Unknown distances rank last, as do unknown insertion times.
+        ranked.sort_by(|(o1, d1, i1), (o2, d2, i2)| {
+            d1.unwrap_or(usize::MAX)
+                .cmp(&d2.unwrap_or(usize::MAX))
+                .then_with(|| i2.cmp(i1))
+                .then_with(|| o1.oid.cmp(&o2.oid))
+        });
+        for (obj, distance, inserted) in ranked {
+            let distance = match distance {
+                Some(d) => format!("distance {}", d),
+                None => "unknown distance".to_string(),
+            };
+            let reason = match policy {
+                SelectionPolicy::Closest => format!("closest to \"{}\" ({})", ancestor, distance),
+                SelectionPolicy::MostRecent => {
+                    match inserted.and_then(|i| SystemTime::now().duration_since(i).ok()) {
+                        Some(age) => format!("most recently inserted ({}s ago)", age.as_secs()),
+                        None => "most recently inserted (unknown time)".to_string(),
+                    }
+                }
+                SelectionPolicy::Verified => match self.verify_entry(root, &obj, artifact) {
+                    Ok(()) => format!(
+                        "checksums verified, closest to \"{}\" ({})",
+                        ancestor, distance
+                    ),
+                    Err(e) => {
+                        warn!("Not using entry of \"{}\" in {:?}: {}", obj, root, e);
+                        continue;
+                    }
+                },
+            };
+            match n_candidates {
+                1 => debug!("Selected \"{}\": {}.", obj, reason),
+                n => info!("Selected \"{}\" of {} candidates: {}.", obj, n, reason),
+            }
+            return Some(obj);
+        }
+        None
+    }
+
+    /// The time at which the entry at `path` was inserted.  For entries inserted by earlier
+    /// versions, which did not record it, this is the modification time of the entry.
+    fn insertion_time(path: &Path) -> Option<SystemTime> {
+        match fs::read_to_string(path.join(INSERTED_FILE)) {
+            Ok(s) => {
+                let (secs, nanos) = s.trim().split_once('.')?;
+                let since_epoch = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+                SystemTime::UNIX_EPOCH.checked_add(since_epoch)
+            }
+            Err(_) => path.metadata().and_then(|meta| meta.modified()).ok(),
+        }
+    }
+
+    /// Checksums of the files in the entry at `path`: one line with the Git blob ID and the
+    /// relative path per file, sorted by path.  The blob ID of a symlink is that of its target.
+    fn checksums(&self, path: &Path) -> Result<String> {
+        let mut lines = Vec::new();
+        for entry in walkdir::WalkDir::new(path).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = entry.map_err(|cause| {
+                Error::chain(format!("Could not read entry {:?}:", path), cause)
+            })?;
+            let rel_path = entry.path().strip_prefix(path).unwrap();
+            if rel_path == Path::new(CHECKSUMS_FILE) || rel_path == Path::new(INSERTED_FILE) {
+                continue;
+            }
+            let file_type = entry.file_type();
+            let oid = if file_type.is_symlink() {
+                let target = fs::read_link(entry.path()).map_err(|cause| {
+                    Error::chain(format!("Could not read link {:?}:", entry.path()), cause)
+                })?;
+                self.repo.hash_object(&target.to_string_lossy())
+            } else if file_type.is_file() {
+                self.repo.hash_file(entry.path())
+            } else {
+                continue;
+            };
+            match oid {
+                Some(oid) => lines.push(format!("{} {}\n", oid, rel_path.to_string_lossy())),
+                None => return Error::result(format!("Could not hash {:?}!", entry.path())),
+            }
+        }
+        Ok(lines.concat())
+    }
+
+    /// Verify the files of the entry of `object` for `artifact` in the store at `root` against
+    /// the checksums recorded on insertion.
+    fn verify_entry(&self, root: &Path, object: &Object, artifact: &Artifact) -> Result<()> {
+        let path = self.object_artifact_path(root, object, artifact)?;
+        let recorded = fs::read_to_string(path.join(CHECKSUMS_FILE)).map_err(|cause| {
+            Error::chain(
+                format!("Could not read checksums of entry {:?}:", path),
+                cause,
+            )
+        })?;
+        match self.checksums(&path)? == recorded {
+            true => Ok(()),
+            false => Error::result(format!("Checksums of entry {:?} do not match!", path)),
+        }
+    }
+
+    /// Find cached object for artifact in the store at `root` (the primary directory or a tier),
+    /// under a read-only lock on the store.  If the store cannot be locked, it is skipped with a
+    /// warning.
+    fn cached_object_in_tier(
+        &self,
+        root: &Path,
+        artifact: &Artifact,
+        ancestor: &Object,
+    ) -> Option<Object> {
+        let _lock = match self.lock_tier_read_only(root) {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("Skipping cache {:?}: {}", root, e);
+                return None;
+            }
+        };
+        self.cached_object_in(root, artifact, ancestor)
+    }
+
+    /// The options for copying entries between stores.  Special files in entries were recreated
+    /// on insertion, so they are recreated as well.
+    fn copy_options(&self) -> CopyOptions {
+        CopyOptions {
+            mtime: match self.mtime_policy {
+                MtimePolicy::Touch => Mtime::Touch,
+                _ => Mtime::Preserve,
+            },
+            ownership: self.preserve_ownership,
+            xattrs: self.preserve_xattrs,
+            threads: self.copy_threads,
+            special_files: SpecialFiles::Recreate,
+            symlinks: Symlinks::Preserve,
+            relative_root: None,
+            confine_symlinks: None,
+        }
+    }
+
+    /// The options for inserting the outputs of `artifact` into the cache.
+    fn insertion_options(&self, artifact: &Artifact) -> CopyOptions {
+        CopyOptions {
+            special_files: artifact.special_files,
+            symlinks: artifact.symlinks,
+            relative_root: Some(self.repo.path().to_path_buf()),
+            ..self.copy_options()
+        }
+    }
+
+    /// The options for retrieving the outputs of `artifact` from the cache.
+    fn retrieval_options(&self, artifact: &Artifact) -> Result<CopyOptions> {
+        let mtime = match self.mtime_policy {
+            MtimePolicy::Touch => Mtime::Touch,
+            MtimePolicy::Preserve => Mtime::Preserve,
+            MtimePolicy::OlderThanInputs => {
+                let inputs: Vec<PathBuf> = artifact
+                    .inputs
+                    .iter()
+                    .map(|inp| self.repo.path().join(inp))
+                    .collect();
+                match crate::fs::oldest_mtime(&inputs)? {
+                    Some(oldest) => Mtime::Set(oldest - Duration::from_secs(1)),
+                    None => Mtime::Preserve,
+                }
+            }
+        };
+        // Symlink policies are applied on insertion, but restored symlinks must never point outside
+        // of the repository.
+        Ok(CopyOptions {
+            mtime,
+            special_files: artifact.special_files,
+            confine_symlinks: Some(self.repo.path().to_path_buf()),
+            ..self.copy_options()
+        })
+    }
+
+    /// Copy the entry at `entry` (relative to the root of a store) from the store at `from` to the
+    /// store at `to`, under a read-write lock on `to`.  Entries that already exist in `to` are not
+    /// copied again.
+    fn copy_entry(&self, from: &Path, to: &Path, entry: &Path) -> Result<()> {
+        let _lock = self.lock_read_write(to)?;
+        let dst = to.join(entry);
+        if dst.exists() {
+            trace!("Entry {:?} already exists in {:?}.", entry, to);
+            return Ok(());
+        }
+        write_entry(&dst, |tmp| {
+            crate::fs::copy_with_options(from.join(entry), tmp, &self.copy_options())
+        })
+        .map_err(|cause| Error::chain(format!("Could not copy entry to {:?}:", to), cause))
     }
 
     pub fn get(
         &self,
-        artifact: &'a Artifact,
+        artifact: &Artifact,
         ignore_uncommitted_changes: bool,
-    ) -> Result<Option<Object<'a>>> {
-        let _lock = self.lock_read_only()?;
-        let obj = self.cached_object(artifact, ignore_uncommitted_changes);
-        if obj.is_none() {
-            return Ok(None);
-        }
-        let obj = obj.unwrap();
-        let path = self.object_artifact_path(&obj, &artifact);
-        debug!("Cache path: {:?}.", path);
-        for oup in &artifact.outputs {
-            let src = path.as_path().join(oup);
-            let dst = self.repo.path.as_path().join(oup);
-            match crate::fs::copy(&src, &dst) {
-                Ok(()) => (),
-                Err(e) => {
-                    return Err(e);
+    ) -> Result<Option<Object>> {
+        self.validate_artifact(artifact)?;
+        let ancestor = match self.required_object(artifact, ignore_uncommitted_changes) {
+            None => return Ok(None),
+            Some(o) => o,
+        };
+        for root in self.stores() {
+            let lock = if root == self.path {
+                Some(self.lock_read_only(root)?)
+            } else {
+                match self.lock_tier_read_only(root) {
+                    Ok(l) => l,
+                    Err(e) => {
+                        warn!("Skipping cache {:?}: {}", root, e);
+                        continue;
+                    }
+                }
+            };
+            let obj = match self.cached_object_in(root, artifact, &ancestor) {
+                None => continue,
+                Some(o) => o,
+            };
+            let path = self.object_artifact_path(root, &obj, artifact)?;
+            debug!("Cache path: {:?}.", path);
+            let options = self.retrieval_options(artifact)?;
+            for oup in &artifact.outputs {
+                let src = path.as_path().join(oup);
+                let dst = self.repo.path().join(oup);
+                if self.clean || artifact.clean {
+                    crate::fs::prune(&src, &dst)?;
+                }
+                match crate::fs::link(&src, &dst, self.link_mode, &options) {
+                    Ok(()) => (),
+                    Err(e) => {
+                        return Err(e);
+                    }
+                }
+            }
+            debug!("Releasing lock."); // TODO: Move this to `Drop` of custom lock trait.
+            drop(lock);
+            // Populate the primary directory with an entry found in a tier.
+            if root != self.path {
+                let entry = self.entry_path(&obj, artifact)?;
+                debug!(
+                    "Copying entry {:?} from {:?} to {:?}.",
+                    entry, root, self.path
+                );
+                if let Err(e) = self.copy_entry(root, &self.path, &entry) {
+                    warn!("{}", e);
                 }
             }
+            return Ok(Some(obj));
         }
-        debug!("Releasing lock."); // TODO: Move this to `Drop` of custom lock trait.
-        Ok(Some(obj))
+        Ok(None)
     }
 
     pub fn insert(
         &self,
-        artifact: &'a Artifact,
+        artifact: &Artifact,
         ignore_uncommitted_changes: bool,
-    ) -> Result<(bool, Object<'a>)> {
-        let _lock = self.lock_read_write()?;
-        let cached_obj = self.cached_object(artifact, ignore_uncommitted_changes);
-        if cached_obj.is_some() {
-            return Ok((false, cached_obj.unwrap()));
-        }
+    ) -> Result<(bool, Object)> {
+        self.validate_artifact(artifact)?;
+        let _lock = self.lock_read_write(&self.path)?;
         let req_obj = match self.required_object(artifact, ignore_uncommitted_changes) {
             None => Error::result(format!(
                 "Could not determine insertion object for {:?}",
@@ -391,55 +1429,116 @@ impl<'a> Cache<'a> {
             )),
             Some(o) => Ok(o),
         }?;
-        let path = self.object_artifact_path(&req_obj, &artifact);
+        let cached_obj = self
+            .cached_object_in(&self.path, artifact, &req_obj)
+            .or_else(|| {
+                self.stores()
+                    .skip(1)
+                    .find_map(|root| self.cached_object_in_tier(root, artifact, &req_obj))
+            });
+        if let Some(obj) = cached_obj {
+            return Ok((false, obj));
+        }
+        let path = self.object_artifact_path(&self.path, &req_obj, artifact)?;
         debug!("Cache path: {:?}.", path);
-        for oup in &artifact.outputs {
-            let src = self.repo.path.as_path().join(oup);
-            let dst = path.as_path().join(oup);
-            match crate::fs::copy(&src, &dst) {
-                Ok(()) => (),
-                Err(e) => {
-                    return Err(e);
+        write_entry(&path, |tmp| {
+            for oup in &artifact.outputs {
+                let src = self.repo.path().join(oup);
+                let dst = tmp.join(oup);
+                crate::fs::copy_with_options(&src, &dst, &self.insertion_options(artifact))?;
+            }
+            let checksums = self.checksums(tmp)?;
+            fs::write(tmp.join(CHECKSUMS_FILE), checksums).map_err(|cause| {
+                Error::chain(
+                    format!("Could not write checksums of entry {:?}:", path),
+                    cause,
+                )
+            })?;
+            let inserted = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            let inserted = format!("{}.{:09}\n", inserted.as_secs(), inserted.subsec_nanos());
+            fs::write(tmp.join(INSERTED_FILE), inserted).map_err(|cause| {
+                Error::chain(
+                    format!("Could not write insertion time of entry {:?}:", path),
+                    cause,
+                )
+            })?;
+            // Protect the entry from being modified through links created by `get`.
+            crate::fs::write_protect(tmp)
+        })?;
+        let entry = self.entry_path(&req_obj, artifact)?;
+        match self.write_policy {
+            WritePolicy::WriteThrough => {
+                for root in self.writable_tiers() {
+                    debug!("Writing entry {:?} through to {:?}.", entry, root);
+                    self.copy_entry(&self.path, root, &entry)?;
                 }
             }
+            WritePolicy::WriteBack if self.writable_tiers().next().is_some() => {
+                debug!("Deferring writing entry {:?} to tiers.", entry);
+                let pending = self.pending_file_path();
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&pending)
+                    .map_err(|cause| {
+                        Error::chain(format!("Could not open {:?}!", pending), cause)
+                    })?;
+                writeln!(file, "{}", entry.to_string_lossy()).map_err(|cause| {
+                    Error::chain(format!("Could not write to {:?}!", pending), cause)
+                })?;
+            }
+            WritePolicy::WriteBack => (),
         }
         debug!("Releasing lock."); // TODO: Move this to `Drop` of custom lock trait.
         Ok((true, req_obj))
     }
 
-    fn objects_identical_for_path(&self, a: &Object, b: &Object, path: &Path) -> bool {
-        let key = (a.oid.clone(), b.oid.clone(), path.to_path_buf());
-        if let Some(entry) = self.objects_path_identity_cache.borrow().get(&key) {
-            return *entry;
-        }
-        let key_mirrored = (b.oid.clone(), a.oid.clone(), path.to_path_buf());
-        if let Some(entry) = self.objects_path_identity_cache.borrow().get(&key_mirrored) {
-            return *entry;
+    /// Write the entries whose writing was deferred by the write-back policy to all tiers that are
+    /// not read-only.
+    /// Returns the number of entries written.
+    pub fn flush(&self) -> Result<usize> {
+        let _lock = self.lock_read_write(&self.path)?;
+        let pending = self.pending_file_path();
+        let contents = match fs::read_to_string(&pending) {
+            Ok(c) => c,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Error::result(format!("Could not read {:?}: {}", pending, e)),
+        };
+        let mut entries: Vec<&str> = contents.lines().filter(|l| !l.is_empty()).collect();
+        entries.sort();
+        entries.dedup();
+        for entry in &entries {
+            for root in self.writable_tiers() {
+                debug!("Writing entry {:?} back to {:?}.", entry, root);
+                self.copy_entry(&self.path, root, Path::new(entry))?;
+            }
         }
-        let entry = a.path_is_same_as(b, path);
-        self.objects_path_identity_cache
-            .borrow_mut()
-            .insert(key, entry);
-        entry
+        fs::remove_file(&pending)
+            .map_err(|cause| Error::chain(format!("Could not remove {:?}!", pending), cause))?;
+        debug!("Releasing lock."); // TODO: Move this to `Drop` of custom lock trait.
+        Ok(entries.len())
     }
 
-    /// Find the cache objects that (all of the following)
+    /// Find the objects in the store at `root` that (all of the following)
     /// - contain `subpath`
-    /// - are descendants of `ancestor` (or `ancestor` itself)
-    /// - correspond to a commit that does not modify `subpath` since `parent`.
+    /// - are descendants of `ancestor` (or `ancestor` itself), unless `cross_branch_hits` is set
+    /// - have inputs identical to those of `ancestor`.
     fn find_candidates(
         &self,
-        ancestor: Object<'a>,
+        root: &Path,
+        ancestor: Object,
         subpath: &Path,
         artifact: &Artifact,
-    ) -> HashSet<Object<'a>> {
+    ) -> HashSet<Object> {
         debug!(
             "Finding candidates for {:?} with ancestor \"{}\":",
             &subpath, &ancestor
         );
         // Simplest case: the required path exists for the ancestor itself.
         let mut set = HashSet::new();
-        let direct_path = self.subpath_in_object(&ancestor, artifact, subpath);
+        let direct_path = self.subpath_in_store(root, &ancestor, artifact, subpath);
         if direct_path.is_some() {
             trace!("Ancestor itself is a candidate.");
             set.insert(ancestor.clone());
@@ -448,20 +1547,23 @@ impl<'a> Cache<'a> {
         }
         // Additionally, we determine all other entries in the cache that match the requirements.
         // Start with all objects in the cache.
-        let objs: HashSet<Object<'a>> = self.objects();
-        let candidates: HashSet<Object<'a>> = objs
+        let objs: HashSet<Object> = self.objects(root);
+        let candidates: HashSet<Object> = objs
             .iter()
             // Reduce to descendant objects.
-            .filter(|obj| obj.is_descendant_of(&ancestor))
+            .filter(|obj| self.cross_branch_hits || obj.is_descendant_of(&ancestor))
             .inspect(|obj| trace!("Descendant: \"{}\"", obj))
             // Reduce to objects that contain the subpath.
-            .filter(|obj| self.subpath_in_object(&obj, artifact, subpath).is_some())
+            .filter(|obj| {
+                self.subpath_in_store(root, &obj, artifact, subpath)
+                    .is_some()
+            })
             .inspect(|obj| trace!("Containing subpath: \"{}\"", obj))
             // Reduce to objects that do not change any of the inputs.
             .filter(|obj| {
                 let mut identical = true;
                 for inp in &artifact.inputs {
-                    if !self.objects_identical_for_path(&obj, &ancestor, inp) {
+                    if !obj.path_is_same_as(&ancestor, inp) {
                         identical = false;
                         break;
                     }
@@ -482,3 +1584,663 @@ impl<'a> Cache<'a> {
         candidates
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git::ObjectFormat;
+    use maplit::btreemap;
+    use tempdir::TempDir;
+
+    /// Run a Git command on `repo` as a test user, asserting that it succeeds.
+    fn git(repo: &Repo, args: &[&str]) {
+        let user = ["-c", "user.name=Test", "-c", "user.email=test@localhost"];
+        let output = repo.cmd_output(&[&user[..], args].concat());
+        assert!(output.is_some(), "git {:?} failed", args);
+    }
+
+    fn setup() -> Result<(Repo, TempDir)> {
+        init(&[])
+    }
+
+    /// Set up a repository created by `git init` with additional `args`.
+    fn init(args: &[&str]) -> Result<(Repo, TempDir)> {
+        let tmp = temp_dir("memora-test-cache")?;
+        git(
+            &Repo::new(tmp.path().to_path_buf()),
+            &[&["init", "-q"][..], args].concat(),
+        );
+        // The object format of the repository is determined when creating a `Repo`.
+        let repo = Repo::new(tmp.path().to_path_buf());
+        Ok((repo, tmp))
+    }
+
+    fn temp_dir(prefix: &str) -> Result<TempDir> {
+        TempDir::new(prefix)
+            .map_err(|cause| Error::chain("Could not create temporary directory:", cause))
+    }
+
+    /// Set up a repository with a committed input `input` and an uncommitted output `output`.
+    fn setup_with_input_and_output() -> Result<(Repo, TempDir)> {
+        add_input_and_output(setup()?)
+    }
+
+    /// Commit an input `input` and create an uncommitted output `output` in a repository.
+    fn add_input_and_output((repo, tmp): (Repo, TempDir)) -> Result<(Repo, TempDir)> {
+        fs::write(tmp.path().join("input"), "input").unwrap();
+        fs::write(tmp.path().join("output"), "output").unwrap();
+        git(&repo, &["add", "input"]);
+        git(&repo, &["commit", "-q", "-m", "Add input"]);
+        Ok((repo, tmp))
+    }
+
+    /// Create a cache without artifacts in a new temporary directory.
+    fn setup_cache(repo: &Repo) -> Result<(Cache, TempDir)> {
+        let cache_dir = temp_dir("memora-test-cache-dir")?;
+        let cache = Cache::new(cache_dir.path().to_path_buf(), repo, &vec![]);
+        Ok((cache, cache_dir))
+    }
+
+    fn artifact(inputs: &[&str], outputs: &[&str]) -> Artifact {
+        Artifact {
+            name: "some_artifact".to_string(),
+            inputs: inputs.iter().map(PathBuf::from).collect(),
+            outputs: outputs.iter().map(PathBuf::from).collect(),
+            clean: false,
+            special_files: SpecialFiles::default(),
+            symlinks: Symlinks::default(),
+            values: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn definition_key_is_normalized() -> Result<()> {
+        let (repo, tmp) = setup()?;
+        let artifacts = vec![];
+        let cache = Cache::new(tmp.path().to_path_buf(), &repo, &artifacts);
+        let key = cache.definition_key(&artifact(&["a", "b"], &["out"]))?;
+        assert_eq!(
+            cache.definition_key(&artifact(&["./b", "a", "a"], &["./out"]))?,
+            key
+        );
+        assert_ne!(
+            cache.definition_key(&artifact(&["a", "b"], &["out2"]))?,
+            key
+        );
+        assert_ne!(cache.definition_key(&artifact(&["a"], &["out"]))?, key);
+        let dereference = Artifact {
+            symlinks: Symlinks::Dereference,
+            ..artifact(&["a", "b"], &["out"])
+        };
+        assert_ne!(cache.definition_key(&dereference)?, key);
+        let skip = Artifact {
+            special_files: SpecialFiles::Skip,
+            ..artifact(&["a", "b"], &["out"])
+        };
+        assert_ne!(cache.definition_key(&skip)?, key);
+        let clean = Artifact {
+            clean: true,
+            ..artifact(&["a", "b"], &["out"])
+        };
+        assert_ne!(cache.definition_key(&clean)?, key);
+        Ok(())
+    }
+
+    #[test]
+    fn tiers_write_through() -> Result<()> {
+        let (repo, tmp) = setup_with_input_and_output()?;
+        let (local, shared) = (
+            temp_dir("memora-test-local")?,
+            temp_dir("memora-test-shared")?,
+        );
+        let art = artifact(&["input"], &["output"]);
+        let artifacts = vec![];
+        let mut cache = Cache::new(local.path().to_path_buf(), &repo, &artifacts);
+        cache.tiers = vec![Tier {
+            path: shared.path().to_path_buf(),
+            read_only: false,
+        }];
+        let (inserted, obj) = cache.insert(&art, false)?;
+        assert!(inserted);
+        let entry = cache.entry_path(&obj, &art)?;
+        assert!(local.path().join(&entry).join("output").is_file());
+        assert!(shared.path().join(&entry).join("output").is_file());
+        assert!(!cache.insert(&art, false)?.0);
+        // An entry found only in the tier is copied to the primary directory.
+        fs::remove_dir_all(local.path().join(obj.oid.as_str())).unwrap();
+        fs::remove_file(tmp.path().join("output")).unwrap();
+        assert_eq!(cache.get(&art, false)?, Some(obj));
+        assert!(tmp.path().join("output").is_file());
+        assert!(local.path().join(&entry).join("output").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn tiers_write_back() -> Result<()> {
+        let (repo, _tmp) = setup_with_input_and_output()?;
+        let (local, shared) = (
+            temp_dir("memora-test-local")?,
+            temp_dir("memora-test-shared")?,
+        );
+        let art = artifact(&["input"], &["output"]);
+        let artifacts = vec![];
+        let mut cache = Cache::new(local.path().to_path_buf(), &repo, &artifacts);
+        cache.tiers = vec![Tier {
+            path: shared.path().to_path_buf(),
+            read_only: false,
+        }];
+        cache.write_policy = WritePolicy::WriteBack;
+        let (inserted, obj) = cache.insert(&art, false)?;
+        assert!(inserted);
+        let entry = cache.entry_path(&obj, &art)?;
+        assert!(local.path().join(&entry).join("output").is_file());
+        assert!(!shared.path().join(&entry).exists());
+        assert_eq!(cache.flush()?, 1);
+        assert!(shared.path().join(&entry).join("output").is_file());
+        assert_eq!(cache.flush()?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_tiers() -> Result<()> {
+        let (repo, tmp) = setup_with_input_and_output()?;
+        let (local, curated) = (
+            temp_dir("memora-test-local")?,
+            temp_dir("memora-test-curated")?,
+        );
+        let art = artifact(&["input"], &["output"]);
+        let artifacts = vec![];
+        // Populate the curated cache.
+        let obj = Cache::new(curated.path().to_path_buf(), &repo, &artifacts)
+            .insert(&art, false)?
+            .1;
+        fs::remove_file(curated.path().join(".lock")).unwrap();
+        let mut cache = Cache::new(local.path().to_path_buf(), &repo, &artifacts);
+        cache.tiers = vec![Tier {
+            path: curated.path().to_path_buf(),
+            read_only: true,
+        }];
+        assert_eq!(cache.cached_object(&art, false), Some(obj.clone()));
+        assert_eq!(cache.insert(&art, false)?, (false, obj.clone()));
+        fs::remove_file(tmp.path().join("output")).unwrap();
+        assert_eq!(cache.get(&art, false)?, Some(obj));
+        assert!(tmp.path().join("output").is_file());
+        // Read-only tiers are neither locked nor written to.
+        assert!(!curated.path().join(".lock").exists());
+        fs::remove_dir_all(curated.path()).unwrap();
+        fs::create_dir(curated.path()).unwrap();
+        fs::remove_dir_all(local.path()).unwrap();
+        fs::create_dir(local.path()).unwrap();
+        assert!(cache.insert(&art, false)?.0);
+        assert_eq!(fs::read_dir(curated.path()).unwrap().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn mtime_policies() -> Result<()> {
+        let (repo, tmp) = setup_with_input_and_output()?;
+        let cache_dir = temp_dir("memora-test-cache-dir")?;
+        let art = artifact(&["input"], &["output"]);
+        let artifacts = vec![];
+        let mut cache = Cache::new(cache_dir.path().to_path_buf(), &repo, &artifacts);
+        let (output, input) = (tmp.path().join("output"), tmp.path().join("input"));
+        let mtime = |p: &Path| p.metadata().unwrap().modified().unwrap();
+        let past = std::time::SystemTime::now() - Duration::from_secs(3600);
+        crate::fs::set_mtime(&output, past)?;
+        cache.mtime_policy = MtimePolicy::Preserve;
+        cache.insert(&art, false)?;
+        fs::remove_file(&output).unwrap();
+        cache.get(&art, false)?;
+        assert_eq!(mtime(&output), past);
+        cache.mtime_policy = MtimePolicy::OlderThanInputs;
+        cache.get(&art, false)?;
+        assert!(mtime(&output) < mtime(&input));
+        cache.mtime_policy = MtimePolicy::Touch;
+        cache.get(&art, false)?;
+        assert!(mtime(&output) > past);
+        Ok(())
+    }
+
+    #[test]
+    fn get_clean() -> Result<()> {
+        let (repo, tmp) = setup_with_input_and_output()?;
+        let cache_dir = temp_dir("memora-test-cache-dir")?;
+        let out_dir = tmp.path().join("out");
+        fs::create_dir(&out_dir).unwrap();
+        fs::write(out_dir.join("plugin.so"), "plugin").unwrap();
+        let art = artifact(&["input"], &["out"]);
+        // `clean` is part of the definition, so the artifact with `clean` has its own entries.
+        let clean_art = Artifact {
+            clean: true,
+            ..art.clone()
+        };
+        let artifacts = vec![];
+        let mut cache = Cache::new(cache_dir.path().to_path_buf(), &repo, &artifacts);
+        cache.insert(&art, false)?;
+        cache.insert(&clean_art, false)?;
+        fs::write(out_dir.join("stale.so"), "stale").unwrap();
+        cache.get(&art, false)?;
+        assert!(out_dir.join("stale.so").exists());
+        cache.get(&clean_art, false)?;
+        assert!(!out_dir.join("stale.so").exists());
+        assert!(out_dir.join("plugin.so").is_file());
+        fs::write(out_dir.join("stale.so"), "stale").unwrap();
+        cache.clean = true;
+        cache.get(&art, false)?;
+        assert!(!out_dir.join("stale.so").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn interrupted_insert() -> Result<()> {
+        let (repo, _tmp) = setup_with_input_and_output()?;
+        let cache_dir = temp_dir("memora-test-cache-dir")?;
+        let art = artifact(&["input"], &["output", "missing"]);
+        let artifacts = vec![];
+        let cache = Cache::new(cache_dir.path().to_path_buf(), &repo, &artifacts);
+        assert!(cache.insert(&art, false).is_err());
+        // Neither a partial entry nor its temporary directory remains.
+        for entry in walkdir::WalkDir::new(cache_dir.path()) {
+            let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+            assert!(name != "output" && !name.contains(".tmp."), "{}", name);
+        }
+        assert!(cache.get(&art, false)?.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn pattern_cannot_escape() -> Result<()> {
+        let (repo, tmp) = setup()?;
+        let artifacts = vec![Artifact {
+            name: "lib-%".to_string(),
+            ..artifact(&["src/%"], &["out/%"])
+        }];
+        let cache = Cache::new(tmp.path().to_path_buf(), &repo, &artifacts);
+        assert_eq!(
+            cache.artifact("lib-foo")?.outputs,
+            vec![PathBuf::from("out/foo")]
+        );
+        assert!(cache.artifact("lib-..").is_err());
+        let artifacts = vec![Artifact {
+            name: "libs/%".to_string(),
+            ..artifact(&["src"], &["out"])
+        }];
+        let cache = Cache::new(tmp.path().to_path_buf(), &repo, &artifacts);
+        assert!(cache.artifact("libs/foo").is_ok());
+        assert!(cache.artifact("libs/..").is_err());
+        // Paths must not leave the repository through symlinks either.
+        std::os::unix::fs::symlink("/tmp", tmp.path().join("link")).unwrap();
+        assert!(cache
+            .validate_artifact(&artifact(&["src"], &["link/out"]))
+            .is_err());
+        assert!(cache
+            .validate_artifact(&artifact(&["src"], &["link"]))
+            .is_ok());
+        // `link/..` resolves to the parent of the target of `link`, not to the repository.
+        assert!(cache
+            .validate_artifact(&artifact(&["src"], &["link/../out"]))
+            .is_err());
+        assert!(cache
+            .validate_artifact(&artifact(&["link/../src"], &["out"]))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pattern_artifacts() -> Result<()> {
+        let (repo, tmp) = setup()?;
+        let artifacts = vec![
+            Artifact {
+                name: "lib-{target}-{config}".to_string(),
+                values: btreemap! {
+                    "config".to_string() => vec!["debug".to_string(), "release".to_string()],
+                    "target".to_string() => vec!["x86".to_string(), "arm".to_string()],
+                },
+                ..artifact(&["src/{target}"], &["out/{target}/{config}"])
+            },
+            Artifact {
+                name: "lib-x86-%".to_string(),
+                ..artifact(&["src/x86"], &["x86/%"])
+            },
+            Artifact {
+                name: "lib-arm-debug".to_string(),
+                ..artifact(&["src/arm"], &["arm"])
+            },
+            Artifact {
+                name: "gen-%".to_string(),
+                ..artifact(&["gen"], &["out/{{target}}/%"])
+            },
+            Artifact {
+                name: "gen-{{all}}".to_string(),
+                ..artifact(&["gen"], &["out/{{target}}"])
+            },
+        ];
+        let cache = Cache::new(tmp.path().to_path_buf(), &repo, &artifacts);
+        let arti = cache.artifact("lib-arm-release")?;
+        assert_eq!(arti.inputs, vec![PathBuf::from("src/arm")]);
+        assert_eq!(arti.outputs, vec![PathBuf::from("out/arm/release")]);
+        // The more specific pattern takes precedence.
+        assert_eq!(
+            cache.artifact("lib-x86-release")?.outputs,
+            vec![PathBuf::from("x86/release")]
+        );
+        assert_eq!(
+            cache.artifact("lib-arm-debug")?.outputs,
+            vec![PathBuf::from("arm")]
+        );
+        // Values that are not listed do not match.
+        assert!(cache.artifact("lib-mips-debug").is_err());
+        // Doubled braces are literal braces.
+        assert_eq!(
+            cache.artifact("gen-foo")?.outputs,
+            vec![PathBuf::from("out/{target}/foo")]
+        );
+        assert_eq!(
+            cache.artifact("gen-{all}")?.outputs,
+            vec![PathBuf::from("out/{target}")]
+        );
+        let names: Vec<String> = cache.instances()?.into_iter().map(|a| a.name).collect();
+        assert_eq!(names, vec!["lib-arm-release", "lib-arm-debug", "gen-{all}"]);
+        Ok(())
+    }
+
+    #[test]
+    fn discover_instances() -> Result<()> {
+        let (repo, tmp) = setup()?;
+        for file in &["libs/foo/a", "libs/bar/b", "libs/baz/c", "src/x86/d"] {
+            crate::fs::create_parents(&tmp.path().join(file))?;
+            fs::write(tmp.path().join(file), "").unwrap();
+        }
+        assert!(repo
+            .cmd_output(&["add", "libs/foo", "libs/bar", "src"])
+            .is_some());
+        let artifacts = vec![
+            Artifact {
+                name: "lib-%".to_string(),
+                ..artifact(&["include", "libs/%"], &["out/%"])
+            },
+            Artifact {
+                name: "tool-{target}-{config}".to_string(),
+                values: btreemap! {
+                    "config".to_string() => vec!["debug".to_string()],
+                },
+                ..artifact(&["src/{target}"], &["out/{target}/{config}"])
+            },
+            Artifact {
+                name: "app-%".to_string(),
+                ..artifact(&["src"], &["out/%"])
+            },
+        ];
+        let cache = Cache::new(tmp.path().to_path_buf(), &repo, &artifacts);
+        let names: Vec<String> = cache.instances()?.into_iter().map(|a| a.name).collect();
+        // `libs/baz` is not tracked, and `app-%` has no input with `%`.
+        assert_eq!(names, vec!["lib-bar", "lib-foo", "tool-x86-debug"]);
+        Ok(())
+    }
+
+    #[test]
+    fn tree_hash_keys() -> Result<()> {
+        let (repo, tmp) = setup_with_input_and_output()?;
+        let (mut cache, cache_dir) = setup_cache(&repo)?;
+        let art = artifact(&["input"], &["output"]);
+        cache.key_mode = KeyMode::TreeHash;
+        let (inserted, obj) = cache.insert(&art, false)?;
+        assert!(inserted);
+        assert!(cache_dir
+            .path()
+            .join("tree")
+            .join(obj.oid.as_str())
+            .is_dir());
+        // An unrelated branch with identical inputs hits the same entry.
+        git(&repo, &["checkout", "-q", "--orphan", "other"]);
+        git(&repo, &["commit", "-q", "--allow-empty", "-m", "unrelated"]);
+        assert_eq!(
+            cache.cached_object(&art, false).map(|o| o.oid),
+            Some(obj.oid.clone())
+        );
+        // Changed inputs do not.
+        fs::write(tmp.path().join("input"), "changed").unwrap();
+        git(&repo, &["commit", "-q", "-am", "changed"]);
+        assert!(cache.cached_object(&art, false).is_none());
+        // Entries keyed by commit are not used.
+        cache.key_mode = KeyMode::Commit;
+        assert!(cache.cached_object(&art, false).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn cross_branch_hits() -> Result<()> {
+        let (repo, tmp) = setup_with_input_and_output()?;
+        let (mut cache, _cache_dir) = setup_cache(&repo)?;
+        let art = artifact(&["input"], &["output"]);
+        let git = |args: &[&str]| git(&repo, args);
+        let commit_input = |branch: &str, content: &str| {
+            git(&["checkout", "-q", "main", "-b", branch]);
+            fs::write(tmp.path().join("input"), content).unwrap();
+            git(&["commit", "-q", "-am", branch]);
+        };
+        // Insert identical inputs on two sibling branches, one further from `main` than the other.
+        commit_input("near", "changed");
+        let (_, near) = cache.insert(&art, false)?;
+        git(&["checkout", "-q", "main", "-b", "far"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "unrelated"]);
+        fs::write(tmp.path().join("input"), "changed").unwrap();
+        git(&["commit", "-q", "-am", "far"]);
+        let (_, far) = cache.insert(&art, false)?;
+        assert_ne!(near.oid, far.oid);
+        // Make the same change on a third branch.
+        commit_input("current", "changed");
+        assert!(cache.cached_object(&art, false).is_none());
+        cache.cross_branch_hits = true;
+        assert_eq!(
+            cache.cached_object(&art, false).map(|o| o.oid),
+            Some(near.oid.clone())
+        );
+        // Entries with different inputs are not used.
+        fs::write(tmp.path().join("input"), "different").unwrap();
+        git(&["commit", "-q", "-am", "different"]);
+        assert!(cache.cached_object(&art, false).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn selection_policies() -> Result<()> {
+        let (repo, tmp) = setup_with_input_and_output()?;
+        let (mut cache, cache_dir) = setup_cache(&repo)?;
+        let art = artifact(&["input"], &["output"]);
+        let git = |args: &[&str]| git(&repo, args);
+        // Insert identical inputs on two sibling branches, where the entry further from the
+        // current commit is inserted more recently.
+        let mut entries = Vec::new();
+        for (branch, n_unrelated) in &[("near", 0), ("far", 2)] {
+            git(&["checkout", "-q", "main", "-b", branch]);
+            for _ in 0..*n_unrelated {
+                git(&["commit", "-q", "--allow-empty", "-m", "unrelated"]);
+            }
+            fs::write(tmp.path().join("input"), "changed").unwrap();
+            git(&["commit", "-q", "-am", branch]);
+            let (_, obj) = cache.insert(&art, false)?;
+            entries.push(cache.object_artifact_path(cache_dir.path(), &obj, &art)?);
+        }
+        // Copying an entry between cache directories gives it a new modification time, which
+        // does not count as insertion.
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        crate::fs::set_mtime(&entries[0], later)?;
+        cache.cross_branch_hits = true;
+        git(&["checkout", "-q", "main", "-b", "current"]);
+        fs::write(tmp.path().join("input"), "changed").unwrap();
+        git(&["commit", "-q", "-am", "current"]);
+        let selected = |cache: &Cache| -> Option<PathBuf> {
+            let obj = cache.cached_object(&art, false)?;
+            cache
+                .object_artifact_path(cache_dir.path(), &obj, &art)
+                .ok()
+        };
+        assert_eq!(selected(&cache).as_ref(), Some(&entries[0]));
+        cache.selection_policy = SelectionPolicy::MostRecent;
+        assert_eq!(selected(&cache).as_ref(), Some(&entries[1]));
+        cache.selection_policy = SelectionPolicy::Verified;
+        assert_eq!(selected(&cache).as_ref(), Some(&entries[0]));
+        // Corrupted entries are not used.
+        fs::write(entries[0].join("output"), "corrupted").unwrap();
+        assert_eq!(selected(&cache).as_ref(), Some(&entries[1]));
+        fs::remove_file(entries[1].join(CHECKSUMS_FILE)).unwrap();
+        assert!(selected(&cache).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn shallow_clones() -> Result<()> {
+        let (upstream, upstream_dir) = setup_with_input_and_output()?;
+        let input_commit = upstream.last_commit_on_path(Path::new("input")).unwrap();
+        for _ in 0..3 {
+            git(
+                &upstream,
+                &["commit", "-q", "--allow-empty", "-m", "unrelated"],
+            );
+        }
+        let clone_dir = temp_dir("memora-test-cache-clone")?;
+        let url = format!("file://{}", upstream_dir.path().display());
+        let clone_path = clone_dir.path().to_str().unwrap();
+        git(
+            &upstream,
+            &["clone", "-q", "--depth", "1", &url, clone_path],
+        );
+        let repo = Repo::new(clone_dir.path().to_path_buf());
+        assert!(repo.is_shallow());
+        fs::write(clone_dir.path().join("output"), "output").unwrap();
+        let art = artifact(&["input"], &["output"]);
+        // The commit that last modified the input is not in the history, so the artifact is keyed
+        // by its input.
+        let (cache, cache_dir) = setup_cache(&repo)?;
+        let (_, obj) = cache.insert(&art, false)?;
+        assert_eq!(Some(obj.oid), repo.tree_key(&art.inputs));
+        assert!(cache_dir.path().join("tree").is_dir());
+        let input_commit = Object::new(input_commit.oid, &repo);
+        let head = repo.cmd_output(&["rev-parse", "HEAD"]).unwrap();
+        let head = Object::new(head.parse()?, &repo);
+        assert!(!input_commit.is_ancestor_of(&head));
+        let boundary = repo.shallow_boundary();
+        // Deepening fetches the commit that last modified the input.
+        let mut cache = Cache::new(cache_dir.path().to_path_buf(), &repo, &vec![]);
+        cache.shallow_policy = ShallowPolicy::Deepen;
+        let (inserted, obj) = cache.insert(&art, false)?;
+        assert!(inserted);
+        assert_eq!(obj.oid, input_commit.oid);
+        assert_ne!(repo.shallow_boundary(), boundary);
+        assert!(input_commit.is_ancestor_of(&head));
+        assert_eq!(cache.cached_object(&art, false), Some(obj));
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_queries() -> Result<()> {
+        fn thread_safe<T: Send + Sync + 'static>() {}
+        thread_safe::<Cache>();
+        thread_safe::<Object>();
+        let (repo, _tmp) = setup_with_input_and_output()?;
+        let cache_dir = temp_dir("memora-test-cache-dir")?;
+        let art = artifact(&["input"], &["output"]);
+        let cache = Cache::new(cache_dir.path().to_path_buf(), &repo, &vec![art.clone()]);
+        drop(repo);
+        let (_, obj) = cache.insert(&art, false)?;
+        let cache = std::sync::Arc::new(cache);
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let (cache, art) = (cache.clone(), art.clone());
+                std::thread::spawn(move || cache.cached_object(&art, false))
+            })
+            .collect();
+        for thread in threads {
+            assert_eq!(thread.join().unwrap().as_ref(), Some(&obj));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn concurrent_insertions() -> Result<()> {
+        let (repo, tmp) = setup_with_input_and_output()?;
+        let (cache_dir, tier_dir) = (
+            temp_dir("memora-test-cache-dir")?,
+            temp_dir("memora-test-tier-dir")?,
+        );
+        let art = artifact(&["input"], &["output"]);
+        // Each thread works in a clone of the repository, so the threads share the cache but not
+        // the outputs.
+        let clones = (0..4)
+            .map(|_| {
+                let dir = temp_dir("memora-test-clone")?;
+                let (from, to) = (tmp.path().to_str().unwrap(), dir.path().to_str().unwrap());
+                assert!(repo.cmd_output(&["clone", "-q", from, to]).is_some());
+                fs::write(dir.path().join("output"), "output").unwrap();
+                Ok(dir)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let tiers = vec![Tier {
+            path: tier_dir.path().to_path_buf(),
+            read_only: false,
+        }];
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = clones
+                .iter()
+                .map(|dir| {
+                    let repo = Repo::new(dir.path().to_path_buf());
+                    let mut cache = Cache::new(cache_dir.path().to_path_buf(), &repo, &vec![]);
+                    cache.tiers = tiers.clone();
+                    cache.write_policy = WritePolicy::WriteBack;
+                    let art = &art;
+                    scope.spawn(move || -> Result<()> {
+                        for _ in 0..8 {
+                            let (_, obj) = cache.insert(art, false)?;
+                            fs::remove_file(dir.path().join("output")).unwrap();
+                            assert_eq!(cache.get(art, false)?, Some(obj));
+                            assert_eq!(
+                                fs::read_to_string(dir.path().join("output")).unwrap(),
+                                "output"
+                            );
+                            cache.flush()?;
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .try_for_each(|thread| thread.join().unwrap())
+        })?;
+        // No temporary directories are left behind.
+        for dir in &[&cache_dir, &tier_dir] {
+            for entry in walkdir::WalkDir::new(dir.path()) {
+                let name = entry.unwrap().file_name().to_string_lossy().into_owned();
+                assert!(!name.contains(".tmp."), "{}", name);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sha256_repositories() -> Result<()> {
+        let (repo, _tmp) = add_input_and_output(init(&["--object-format=sha256"])?)?;
+        let (mut cache, cache_dir) = setup_cache(&repo)?;
+        let art = artifact(&["input"], &["output"]);
+        let (_, obj) = cache.insert(&art, false)?;
+        assert_eq!(obj.oid.format(), ObjectFormat::Sha256);
+        assert_eq!(cache.definition_key(&art)?.format(), ObjectFormat::Sha256);
+        // Entries of SHA-1 repositories in the same cache are ignored.
+        fs::create_dir(cache_dir.path().join("0".repeat(40))).unwrap();
+        assert_eq!(
+            cache
+                .objects(cache_dir.path())
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec![obj.clone()]
+        );
+        git(&repo, &["commit", "-q", "--allow-empty", "-m", "Unrelated"]);
+        assert_eq!(cache.cached_object(&art, false), Some(obj));
+        cache.key_mode = KeyMode::TreeHash;
+        let (_, obj) = cache.insert(&art, false)?;
+        assert_eq!(obj.oid.format(), ObjectFormat::Sha256);
+        assert_eq!(cache.cached_object(&art, false), Some(obj));
+        Ok(())
+    }
+}
@@ -4,19 +4,27 @@
 
 //! Command-Line Interface
 
-use crate::cache::Cache;
+use crate::cache::{Artifact, Cache, Tier};
 use crate::config::Manifest;
 use crate::error::{Error, Result};
 use crate::git::Repo;
+use crate::settings::Settings;
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 use inflector::Inflector;
-use log::{debug, info};
+use log::{debug, error, info, warn};
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 
 pub fn main() -> Result<bool> {
-    env_logger::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    // Load settings before initializing the logger, which depends on them.
+    let settings = Settings::load(&Settings::default_paths(), |var| env::var(var).ok());
+    let log_level = match &settings {
+        Ok(s) => s.log_level.value.clone(),
+        Err(_) => Settings::default().log_level.value,
+    };
+    env_logger::from_env(env_logger::Env::default().default_filter_or(log_level)).init();
+    let mut settings = settings?;
     let name = env!("CARGO_PKG_NAME").to_title_case();
     let version = env!("CARGO_PKG_VERSION");
 
@@ -30,16 +38,39 @@ pub fn main() -> Result<bool> {
             .takes_value(true)
             .help("Run as if started in this path.")
     )
+    .arg(Arg::with_name("manifest")
+            .long("manifest")
+            .env("MEMORA_MANIFEST")
+            .takes_value(true)
+            .help("Path of the manifest (relative to the working directory).  If not given, the manifest is searched in the repository.")
+    )
     .arg(Arg::with_name("ignore_uncommitted_changes")
             .long("ignore-uncommitted-changes")
             .help("Ignores uncommitted changes")
     )
+    .subcommand(SubCommand::with_name("check")
+            .about("Check the manifest for problems.  Exit zero iff no problems are found.")
+    )
+    .subcommand(SubCommand::with_name("settings")
+            .about("Show the settings and where each of them comes from.")
+    )
     .subcommand(SubCommand::with_name("get")
             .about("Get the outputs of an artifact from the cache or exit non-zero if the artifact is not cached.")
             .arg(Arg::with_name("artifact")
                     .takes_value(true)
                     .required(true)
              )
+            .arg(Arg::with_name("link_mode")
+                    .long("link-mode")
+                    .takes_value(true)
+                    .possible_values(&["copy", "hardlink", "reflink", "symlink"])
+                    .default_value("copy")
+                    .help("How to transfer the outputs from the cache.  Falls back to copying where the mode is not possible.")
+             )
+            .arg(Arg::with_name("clean")
+                    .long("clean")
+                    .help("Remove files in the outputs that do not exist in the cache, so the outputs mirror the cache exactly.")
+             )
     )
     .subcommand(SubCommand::with_name("insert")
             .about("Insert the outputs of an artifact into the cache.")
@@ -48,6 +79,39 @@ pub fn main() -> Result<bool> {
                     .required(true)
              )
     )
+    .subcommand(SubCommand::with_name("flush")
+            .about("Write entries inserted into the local cache with the write-back policy to the cache root directory.")
+    )
+    .subcommand(SubCommand::with_name("list")
+            .about("List the names of all artifacts, including the instantiations of pattern artifacts with values for all wildcards.")
+    )
+    .subcommand(SubCommand::with_name("status")
+            .about("Show for each artifact (by default, all artifacts listed by `list`) whether it is cached.")
+            .arg(Arg::with_name("artifacts")
+                    .takes_value(true)
+                    .multiple(true)
+             )
+    )
+    .subcommand(SubCommand::with_name("run")
+            .about("Get each artifact from the cache; for each artifact that is not cached, run a command to build it and insert it into the cache.  Exit zero iff all artifacts could be obtained.")
+            .arg(Arg::with_name("all")
+                    .long("all")
+                    .conflicts_with("artifacts")
+                    .help("Run for all artifacts listed by `list`.")
+             )
+            .arg(Arg::with_name("artifacts")
+                    .takes_value(true)
+                    .multiple(true)
+                    .required_unless("all")
+             )
+            .arg(Arg::with_name("command")
+                    .takes_value(true)
+                    .multiple(true)
+                    .last(true)
+                    .required(true)
+                    .help("Command (after `--`) that builds an artifact.  The name of the artifact is in the `MEMORA_ARTIFACT` environment variable.")
+             )
+    )
     .subcommand(SubCommand::with_name("lookup")
             .about("Look an artifact up in the cache.  Exit zero iff the artifact is cached.")
             .arg(Arg::with_name("artifact")
@@ -78,7 +142,7 @@ pub fn main() -> Result<bool> {
 
     // Find Git repository in working directory.
     let repo: Repo = {
-        let tmp = Repo::new(working_dir);
+        let tmp = Repo::new(working_dir.clone());
         let git_path = match tmp.cmd_output(&["rev-parse", "--show-toplevel"]) {
             None => Error::result(format!("Could not find Git repository.")),
             Some(s) => fs::canonicalize(&s).map_err(|cause| {
@@ -92,25 +156,52 @@ pub fn main() -> Result<bool> {
     };
     debug!("Git repository: {:?}.", repo);
 
-    // Find manifest in repository.
-    let manifest: Manifest = {
-        let manifest_path = {
-            let mut iter = ["Memora.yml", ".ci/Memora.yml", ".gitlab-ci.d/Memora.yml"]
-                .iter()
-                .map(|s| Path::new(s))
-                .map(|p| repo.path.join(p))
-                .map(|p| fs::canonicalize(p))
-                .filter(|r| r.is_ok())
-                .map(|r| r.unwrap());
-            match iter.next() {
-                None => Error::result(format!("Could not find Memora manifest.")),
+    // Find manifest in repository, unless it is given explicitly.
+    let manifest_path = {
+        let path = match matches.value_of("manifest") {
+            Some(p) => Ok(working_dir.join(p)),
+            None => match Manifest::find(repo.path()) {
+                None => Error::result("Could not find Memora manifest."),
                 Some(p) => Ok(p),
-            }?
+            },
         };
-        Manifest::from_path(&manifest_path)?
+        path.and_then(|path| {
+            fs::canonicalize(&path).map_err(|cause| {
+                Error::chain(
+                    format!("Failed to canonicalize path of manifest {:?}!", path),
+                    cause,
+                )
+            })
+        })
     };
+
+    // Showing the settings must not require a (valid) manifest.
+    if let ("settings", Some(_)) = matches.subcommand() {
+        match manifest_path.and_then(|path| {
+            Manifest::from_path(&path, repo.path()).map(|manifest| (manifest, path))
+        }) {
+            Ok((manifest, path)) => settings.supply_from_manifest(&manifest, &path),
+            Err(e) => warn!("Showing the settings without the manifest: {}", e),
+        }
+        info!("Settings:\n{}", settings);
+        return Ok(true);
+    }
+
+    let manifest_path = manifest_path?;
+    debug!("Memora manifest path: {:?}.", manifest_path);
+
+    // Checking the manifest must not require a valid manifest.
+    if let ("check", Some(_)) = matches.subcommand() {
+        return check(&repo, &manifest_path);
+    }
+
+    let manifest = Manifest::from_path(&manifest_path, repo.path())?;
     debug!("Memora manifest: {:?}.", manifest);
 
+    settings.supply_from_manifest(&manifest, &manifest_path);
+    debug!("Settings:\n{}", settings);
+    repo.persist_memo(settings.memo_max_entries.value);
+
     let disabled = match manifest.disable_env_var {
         Some(e) => match env::var(&e) {
             Ok(_) => {
@@ -126,18 +217,63 @@ pub fn main() -> Result<bool> {
     };
 
     // Initialize cache.
-    let cache: Cache = {
-        let root_dir = match manifest.cache_root_dir.is_absolute() {
-            true => manifest.cache_root_dir.clone(),
-            false => repo.path.join(&manifest.cache_root_dir),
-        };
+    let mut cache: Cache = {
+        let root_dir = match &settings.cache_root_dir {
+            None => {
+                Error::result("No cache root directory configured in the manifest or the settings.")
+            }
+            Some(s) if s.value.is_absolute() => Ok(s.value.clone()),
+            Some(s) => Ok(repo.path().join(&s.value)),
+        }?;
         let cache_path = fs::canonicalize(&root_dir).map_err(|cause| {
             Error::chain(
                 format!("Failed to canonicalize path of cache {:?}!", root_dir),
                 cause,
             )
         })?;
-        Cache::new(cache_path, &repo, &manifest.artifacts)
+        let mut cache = match &settings.local_cache_dir {
+            None => Cache::new(cache_path, &repo, &manifest.artifacts),
+            Some(s) => {
+                let local_dir = repo.path().join(&s.value);
+                crate::fs::create_dir(&local_dir)?;
+                let local_path = fs::canonicalize(&local_dir).map_err(|cause| {
+                    Error::chain(
+                        format!(
+                            "Failed to canonicalize path of local cache {:?}!",
+                            local_dir
+                        ),
+                        cause,
+                    )
+                })?;
+                let mut cache = Cache::new(local_path, &repo, &manifest.artifacts);
+                cache.tiers = vec![Tier {
+                    path: cache_path,
+                    read_only: false,
+                }];
+                cache
+            }
+        };
+        for tier in &settings.fallback_cache_dirs.value {
+            let dir = repo.path().join(&tier.path);
+            match fs::canonicalize(&dir) {
+                Ok(path) => cache.tiers.push(Tier {
+                    path,
+                    ..tier.clone()
+                }),
+                Err(e) => warn!("Skipping fallback cache {:?}: {}", dir, e),
+            }
+        }
+        cache.write_policy = settings.write_policy.value;
+        cache.mtime_policy = settings.mtimes.value;
+        cache.preserve_ownership = settings.preserve_ownership.value;
+        cache.preserve_xattrs = settings.preserve_xattrs.value;
+        cache.copy_threads = settings.copy_threads.value;
+        cache.key_mode = settings.key_mode.value;
+        cache.cross_branch_hits = settings.cross_branch_hits.value;
+        cache.selection_policy = settings.selection_policy.value;
+        cache.shallow_policy = settings.shallow_policy.value;
+        cache.lock_timeout = settings.lock_timeout.as_ref().map(|s| s.value);
+        cache
     };
     debug!("Cache: {:?}.", cache);
 
@@ -148,13 +284,20 @@ pub fn main() -> Result<bool> {
 
     match matches.subcommand() {
         ("get", Some(matches)) => match disabled {
-            false => get(&cache, matches, ignore_uncommitted_changes),
+            false => get(&mut cache, matches, ignore_uncommitted_changes),
             true => Ok(false),
         },
         ("insert", Some(matches)) => match disabled {
             false => insert(&cache, matches, ignore_uncommitted_changes),
             true => Ok(true),
         },
+        ("flush", Some(_)) => match disabled {
+            false => flush(&cache),
+            true => Ok(true),
+        },
+        ("list", Some(_)) => list(&cache),
+        ("status", Some(matches)) => status(&cache, matches, ignore_uncommitted_changes),
+        ("run", Some(matches)) => run(&cache, matches, ignore_uncommitted_changes, disabled),
         ("lookup", Some(matches)) => match disabled {
             false => lookup(&cache, matches, ignore_uncommitted_changes),
             true => Ok(false),
@@ -170,8 +313,33 @@ fn artifact_name<'a>(matches: &'a ArgMatches) -> Result<&'a str> {
     }
 }
 
-pub fn get(cache: &Cache, matches: &ArgMatches, ignore_uncommitted_changes: bool) -> Result<bool> {
+pub fn check(repo: &Repo, manifest_path: &Path) -> Result<bool> {
+    let issues = Manifest::check(manifest_path, repo.path(), Some(repo))?;
+    for issue in &issues {
+        error!("{}", issue);
+    }
+    match issues.len() {
+        0 => {
+            info!("Manifest {:?} has no problems.", manifest_path);
+            Ok(true)
+        }
+        n => {
+            info!("Manifest {:?} has {} problem(s).", manifest_path, n);
+            Ok(false)
+        }
+    }
+}
+
+pub fn get(
+    cache: &mut Cache,
+    matches: &ArgMatches,
+    ignore_uncommitted_changes: bool,
+) -> Result<bool> {
     let artifact_name = artifact_name(matches)?;
+    if let Some(mode) = matches.value_of("link_mode") {
+        cache.link_mode = mode.parse()?;
+    }
+    cache.clean = matches.is_present("clean");
     let artifact = cache.artifact(artifact_name)?;
     match cache.get(&artifact, ignore_uncommitted_changes) {
         Ok(Some(obj)) => {
@@ -212,6 +380,88 @@ pub fn insert(
     }
 }
 
+pub fn flush(cache: &Cache) -> Result<bool> {
+    let n_entries = cache.flush()?;
+    info!("Wrote {} pending entries to the cache tiers.", n_entries);
+    Ok(true)
+}
+
+pub fn list(cache: &Cache) -> Result<bool> {
+    for artifact in cache.instances()? {
+        println!("{}", artifact.name);
+    }
+    Ok(true)
+}
+
+/// Artifacts named in the `artifacts` argument, or all artifacts if none are named.
+fn artifacts(cache: &Cache, matches: &ArgMatches) -> Result<Vec<Artifact>> {
+    match matches.values_of("artifacts") {
+        Some(names) => names.map(|name| cache.artifact(name)).collect(),
+        None => cache.instances(),
+    }
+}
+
+pub fn status(
+    cache: &Cache,
+    matches: &ArgMatches,
+    ignore_uncommitted_changes: bool,
+) -> Result<bool> {
+    for artifact in artifacts(cache, matches)? {
+        match cache.cached_object(&artifact, ignore_uncommitted_changes) {
+            Some(obj) => println!("{}: cached in {}", artifact.name, obj.oid),
+            None => println!("{}: not cached", artifact.name),
+        }
+    }
+    Ok(true)
+}
+
+pub fn run(
+    cache: &Cache,
+    matches: &ArgMatches,
+    ignore_uncommitted_changes: bool,
+    disabled: bool,
+) -> Result<bool> {
+    let command: Vec<&str> = matches
+        .values_of("command")
+        .map(|values| values.collect())
+        .unwrap_or_default();
+    let (program, args) = match command.split_first() {
+        None => return Error::result("Required \"command\" argument was not provided!"),
+        Some(c) => c,
+    };
+    let mut success = true;
+    for artifact in artifacts(cache, matches)? {
+        if !disabled {
+            if let Some(obj) = cache.get(&artifact, ignore_uncommitted_changes)? {
+                info!("Got artifact \"{}\" from {:?}.", artifact.name, obj.oid);
+                continue;
+            }
+        }
+        info!("Building artifact \"{}\".", artifact.name);
+        let status = std::process::Command::new(program)
+            .args(args)
+            .env("MEMORA_ARTIFACT", &artifact.name)
+            .status()
+            .map_err(|cause| Error::chain(format!("Could not run {:?}!", program), cause))?;
+        if !status.success() {
+            error!(
+                "Building artifact \"{}\" failed with {}.",
+                artifact.name, status
+            );
+            success = false;
+            continue;
+        }
+        if !disabled {
+            let (_, obj) = cache.insert(&artifact, ignore_uncommitted_changes)?;
+            info!(
+                "Inserted artifact \"{}\" under {:?}.",
+                artifact.name, obj.oid
+            );
+        }
+    }
+    Ok(success)
+}
+
 pub fn lookup(
     cache: &Cache,
     matches: &ArgMatches,
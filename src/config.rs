@@ -5,18 +5,36 @@
 //! Configuration
 
 extern crate tuple_vec_map;
-use crate::cache::{Artifact, Artifacts};
+use crate::cache::{Artifact, Artifacts, KeyMode, Tier};
 use crate::error::{Error, Result};
+use crate::fs::{SpecialFiles, Symlinks};
+use crate::git::Repo;
+use crate::pattern;
+use crate::util::{normalize_path, resolve_path};
 use serde::Deserialize;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
 
 /// A Memora build artifact cache manifest.
 #[derive(Debug)]
 pub struct Manifest {
-    /// The root directory of the build artifact cache for a Git repository.
+    /// The root directory of the build artifact cache for a Git repository, unless it is
+    /// configured by the [Settings](../settings/struct.Settings.html).
     ///
     /// The path can be absolute or relative to the root of the Git repository.
-    pub cache_root_dir: PathBuf,
+    pub cache_root_dir: Option<PathBuf>,
+    /// Further cache directories that are searched after the cache root directory, unless they are
+    /// configured by the [Settings](../settings/struct.Settings.html).
+    ///
+    /// The paths can be absolute or relative to the root of the Git repository.
+    pub fallback_cache_dirs: Vec<Tier>,
+    /// How the entries of the cache are keyed, unless this is configured by the
+    /// [Settings](../settings/struct.Settings.html).
+    pub key_mode: Option<KeyMode>,
     /// The Artifacts.
     ///
     /// Each Artifact must have a name.  This name is used as `artifact` argument to Memora
@@ -30,51 +48,1064 @@ pub struct Manifest {
 }
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct SerdeArtifact {
     inputs: Vec<PathBuf>,
     outputs: Vec<PathBuf>,
+    #[serde(default)]
+    clean: bool,
+    #[serde(default)]
+    special_files: SpecialFiles,
+    #[serde(default)]
+    symlinks: Symlinks,
+    #[serde(default)]
+    values: BTreeMap<String, Vec<String>>,
 }
 
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 struct SerdeManifest {
-    pub cache_root_dir: PathBuf,
-    #[serde(with = "tuple_vec_map")]
+    pub cache_root_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub fallback_cache_dirs: Vec<Tier>,
+    pub key_mode: Option<KeyMode>,
+    #[serde(default)]
+    pub include: Vec<PathBuf>,
+    #[serde(default, with = "tuple_vec_map")]
     pub artifacts: Vec<(String, SerdeArtifact)>,
     pub disable_env_var: Option<String>,
 }
 
+/// A position in a manifest file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    /// Line number, starting at 1.
+    pub line: usize,
+    /// Column number, starting at 1.
+    pub column: usize,
+}
+
+impl Location {
+    /// The location of byte offset `offset` in `source`.
+    fn from_offset(source: &str, offset: usize) -> Location {
+        let before = &source[..offset.min(source.len())];
+        let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+        Location {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+}
+
+impl From<Marker> for Location {
+    fn from(marker: Marker) -> Location {
+        Location {
+            line: marker.line(),
+            column: marker.col() + 1,
+        }
+    }
+}
+
+/// A problem found in a manifest file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    /// Path of the manifest file.
+    pub path: PathBuf,
+    /// Position of the problem in the manifest file, if known.
+    pub location: Option<Location>,
+    /// Description of the problem.
+    pub message: String,
+}
+
+impl Display for Issue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.location {
+            Some(loc) => write!(
+                f,
+                "{}:{}:{}: {}",
+                self.path.display(),
+                loc.line,
+                loc.column,
+                self.message
+            ),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+/// Positions of the nodes in a YAML or TOML document, indexed by the sequence of mapping keys and
+/// sequence indices leading to each node.
+#[derive(Default)]
+struct Locations {
+    /// Positions of all occurrences of each mapping key.
+    keys: HashMap<Vec<String>, Vec<Location>>,
+    /// Positions of values.
+    values: HashMap<Vec<String>, Location>,
+    /// Keys and indices leading to the current node.
+    node_path: Vec<String>,
+    /// Open collections, each with the next mapping key or sequence index.
+    collections: Vec<Collection>,
+}
+
+enum Collection {
+    /// A mapping that expects a key if `None` and a value for the contained key otherwise.
+    Mapping(Option<String>),
+    /// A sequence that expects the value with the contained index.
+    Sequence(usize),
+}
+
+impl Locations {
+    fn parse(source: &str) -> Locations {
+        let mut locations = Locations::default();
+        // Syntax errors are reported by the deserializer, so positions are best effort.
+        let _ = Parser::new(source.chars()).load(&mut locations, false);
+        locations
+    }
+
+    /// Determine the positions of the nodes in a TOML document.
+    fn parse_toml(source: &str) -> Locations {
+        let mut locations = Locations::default();
+        // Syntax errors are reported by the deserializer, so positions are best effort.
+        if let Ok(document) = toml_edit::ImDocument::parse(source) {
+            locations.add_toml_table(source, document.as_table());
+        }
+        locations
+    }
+
+    /// Enter the value of `key`, recording the position of `parsed_key`, and return that position.
+    fn enter_toml_key(
+        &mut self,
+        source: &str,
+        key: &str,
+        parsed_key: Option<&toml_edit::Key>,
+    ) -> Option<Location> {
+        self.node_path.push(key.to_string());
+        let location = parsed_key
+            .and_then(|k| k.span())
+            .map(|span| Location::from_offset(source, span.start));
+        if let Some(loc) = location {
+            self.keys.insert(self.node_path.clone(), vec![loc]);
+        }
+        location
+    }
+
+    /// Record the positions of the entries of a TOML table under the current node path.
+    fn add_toml_table(&mut self, source: &str, table: &toml_edit::Table) {
+        for (key, item) in table.iter() {
+            let key_location = self.enter_toml_key(source, key, table.key(key));
+            // Implicit tables (e.g., `artifacts` in `[artifacts.foo]`) have no position of their
+            // own, so they are located at their key.
+            let location = item
+                .span()
+                .map(|span| Location::from_offset(source, span.start))
+                .or(key_location);
+            if let Some(loc) = location {
+                self.values.insert(self.node_path.clone(), loc);
+            }
+            match item {
+                toml_edit::Item::Table(table) => self.add_toml_table(source, table),
+                toml_edit::Item::ArrayOfTables(tables) => {
+                    for (idx, table) in tables.iter().enumerate() {
+                        self.node_path.push(idx.to_string());
+                        if let Some(span) = table.span() {
+                            let loc = Location::from_offset(source, span.start);
+                            self.values.insert(self.node_path.clone(), loc);
+                        }
+                        self.add_toml_table(source, table);
+                        self.node_path.pop();
+                    }
+                }
+                toml_edit::Item::Value(value) => self.add_toml_value(source, value),
+                toml_edit::Item::None => (),
+            }
+            self.node_path.pop();
+        }
+    }
+
+    /// Record the positions of the elements of a TOML value under the current node path.
+    fn add_toml_value(&mut self, source: &str, value: &toml_edit::Value) {
+        let add_element = |locations: &mut Locations, value: &toml_edit::Value| {
+            if let Some(span) = value.span() {
+                let loc = Location::from_offset(source, span.start);
+                locations.values.insert(locations.node_path.clone(), loc);
+            }
+            locations.add_toml_value(source, value);
+            locations.node_path.pop();
+        };
+        match value {
+            toml_edit::Value::Array(array) => {
+                for (idx, element) in array.iter().enumerate() {
+                    self.node_path.push(idx.to_string());
+                    add_element(self, element);
+                }
+            }
+            toml_edit::Value::InlineTable(table) => {
+                for (key, element) in table.iter() {
+                    self.enter_toml_key(source, key, table.key(key));
+                    add_element(self, element);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Return the node path element for the `occurrence`-th (starting at 0) occurrence of `key` in
+    /// a mapping, so that the values of duplicate keys can be told apart.
+    fn element(key: &str, occurrence: usize) -> String {
+        match occurrence {
+            0 => key.to_string(),
+            n => format!("{}#{}", key, n),
+        }
+    }
+
+    fn key(&self, node_path: &[&str], occurrence: usize) -> Option<Location> {
+        let node_path: Vec<String> = node_path.iter().map(|s| s.to_string()).collect();
+        self.keys
+            .get(&node_path)
+            .and_then(|locs| locs.get(occurrence))
+            .copied()
+    }
+
+    fn value(&self, node_path: &[&str]) -> Option<Location> {
+        let node_path: Vec<String> = node_path.iter().map(|s| s.to_string()).collect();
+        self.values.get(&node_path).copied()
+    }
+
+    /// Enter the next value of the current collection.
+    fn enter_value(&mut self) {
+        match self.collections.last() {
+            // Complex mapping keys are not used in manifests and are tracked as an empty key.
+            Some(Collection::Mapping(key)) => self.node_path.push(key.clone().unwrap_or_default()),
+            Some(Collection::Sequence(idx)) => self.node_path.push(idx.to_string()),
+            None => (),
+        }
+    }
+
+    /// Leave the value of the current collection and advance to the next key or index.
+    fn leave_value(&mut self) {
+        match self.collections.last_mut() {
+            Some(Collection::Mapping(key)) => {
+                self.node_path.pop();
+                *key = None;
+            }
+            Some(Collection::Sequence(idx)) => {
+                self.node_path.pop();
+                *idx += 1;
+            }
+            None => (),
+        }
+    }
+}
+
+impl MarkedEventReceiver for Locations {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        // Record the position of a mapping key and expect its value next.
+        if let Some(Collection::Mapping(key @ None)) = self.collections.last_mut() {
+            if let Event::Scalar(value, ..) = &ev {
+                let mut key_path = self.node_path.clone();
+                key_path.push(value.clone());
+                let occurrences = self.keys.entry(key_path).or_default();
+                *key = Some(Locations::element(value, occurrences.len()));
+                occurrences.push(mark.into());
+                return;
+            }
+        }
+        match ev {
+            Event::Scalar(..) | Event::Alias(_) => {
+                self.enter_value();
+                self.values.insert(self.node_path.clone(), mark.into());
+                self.leave_value();
+            }
+            Event::MappingStart(_) => {
+                self.enter_value();
+                self.values.insert(self.node_path.clone(), mark.into());
+                self.collections.push(Collection::Mapping(None));
+            }
+            Event::SequenceStart(_) => {
+                self.enter_value();
+                self.values.insert(self.node_path.clone(), mark.into());
+                self.collections.push(Collection::Sequence(0));
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.collections.pop();
+                self.leave_value();
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A manifest file as read from disk.
+struct ManifestFile {
+    /// Canonical path of the file.
+    path: PathBuf,
+    /// Directory of the file relative to the root of the repository.  Artifact names and paths
+    /// defined in an included file are relative to this directory.  Empty for the root manifest,
+    /// whose paths are relative to the root of the repository wherever the file is located.
+    prefix: PathBuf,
+    /// Paths of this file and of all files through which it has been included, to detect cycles.
+    chain: Vec<PathBuf>,
+    locations: Locations,
+    manifest: SerdeManifest,
+}
+
+impl ManifestFile {
+    fn issue(&self, location: Option<Location>, message: String) -> Issue {
+        Issue {
+            path: self.path.clone(),
+            location,
+            message,
+        }
+    }
+
+    /// Return the name of an artifact defined in this file, namespaced by the directory of the file.
+    fn artifact_name(&self, name: &str) -> String {
+        match self.prefix.to_str() {
+            Some("") | None => name.to_string(),
+            Some(prefix) => format!("{}/{}", pattern::escape(prefix), name),
+        }
+    }
+}
+
+/// File names of Manifests, in order of precedence.
+pub const MANIFEST_FILE_NAMES: &[&str] = &["Memora.yml", "Memora.toml", "Memora.json"];
+
+/// Directories, relative to the root of a repository, in which Manifests are searched, in order of
+/// precedence.
+pub const MANIFEST_DIRS: &[&str] = &["", ".ci", ".gitlab-ci.d", ".github"];
+
+/// The format of a Manifest file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Determine the format of a Manifest file from the extension of its path: `.toml` for TOML,
+    /// `.json` for JSON, and YAML otherwise.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+
+    /// Deserialize a Manifest in this format, returning the location and message of an error.
+    fn parse(self, source: &str) -> std::result::Result<SerdeManifest, (Option<Location>, String)> {
+        match self {
+            Format::Yaml => serde_yaml::from_str(source).map_err(|cause| {
+                let location = cause.location().map(|loc| Location {
+                    line: loc.line(),
+                    column: loc.column(),
+                });
+                (location, cause.to_string())
+            }),
+            Format::Toml => toml::from_str(source).map_err(|cause| {
+                let location = cause.line_col().map(|(line, column)| Location {
+                    line: line + 1,
+                    column: column + 1,
+                });
+                (location, cause.to_string())
+            }),
+            Format::Json => serde_json::from_str(source).map_err(|cause| {
+                let location = Some(Location {
+                    line: cause.line(),
+                    column: cause.column(),
+                });
+                (location, cause.to_string())
+            }),
+        }
+    }
+}
+
 impl Manifest {
-    /// Load a Manifest from the file at `path`.
+    /// Find the Manifest of the repository at `root_dir`.
     ///
-    /// This function deserializes the Manifest file and adds the given `path` as input to each
-    /// artifact.
-    pub fn from_path(path: &Path) -> Result<Manifest> {
-        use std::fs::File;
-        let file = File::open(path)
-            .map_err(|cause| Error::chain(format!("Cannot open manifest {:?}!", path), cause))?;
-        let manifest = {
-            let serde_manifest: SerdeManifest = serde_yaml::from_reader(file).map_err(|cause| {
-                Error::chain(format!("Syntax error in manifest {:?}!", path), cause)
-            })?;
-            let mut manifest = Manifest {
-                cache_root_dir: serde_manifest.cache_root_dir,
-                artifacts: serde_manifest
+    /// The Manifest is searched in each of the [`MANIFEST_DIRS`](constant.MANIFEST_DIRS.html)
+    /// under each of the [`MANIFEST_FILE_NAMES`](constant.MANIFEST_FILE_NAMES.html).
+    pub fn find(root_dir: &Path) -> Option<PathBuf> {
+        MANIFEST_DIRS
+            .iter()
+            .find_map(|dir| Manifest::find_in_dir(&root_dir.join(dir)))
+    }
+
+    /// Find a Manifest file directly inside `dir`.
+    fn find_in_dir(dir: &Path) -> Option<PathBuf> {
+        MANIFEST_FILE_NAMES
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+    }
+
+    /// Load a Manifest from the file at `path`, where `root_dir` is the root of the Git
+    /// repository.
+    ///
+    /// This function deserializes the Manifest file and all files it includes, validates them (see
+    /// [`check`](#method.check), except for the checks that require a Git repository).  If the
+    /// Manifest has any problems, the returned error lists all of them.
+    ///
+    /// The Manifest files themselves are not inputs of the artifacts.  Instead, the cache takes the
+    /// definition of each artifact into account (see
+    /// [`Artifact::inputs`](../cache/struct.Artifact.html#structfield.inputs)).
+    ///
+    /// ## Includes
+    ///
+    /// The `include` key of a Manifest file lists paths to further Manifest files, relative to the
+    /// directory of the including file.  If a path points to a directory, the Manifest file in that
+    /// directory (see [`MANIFEST_FILE_NAMES`](constant.MANIFEST_FILE_NAMES.html)) is included.  An
+    /// included file may only define `artifacts` and further `include`s.  The names of its
+    /// artifacts are prefixed with the directory of the included file relative to `root_dir`
+    /// (e.g., artifact `foo` in `libs/bar/Memora.yml` is named `libs/bar/foo`), and its input and
+    /// output paths are relative to that directory.
+    pub fn from_path(path: &Path, root_dir: &Path) -> Result<Manifest> {
+        let (files, issues) = Manifest::load(path, root_dir, None)?;
+        if !issues.is_empty() {
+            return Error::result(format!(
+                "Manifest {:?} is invalid:\n{}",
+                path,
+                issues
+                    .iter()
+                    .map(|issue| issue.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+        let root = &files[0].manifest;
+        let mut manifest = Manifest {
+            cache_root_dir: root.cache_root_dir.clone(),
+            fallback_cache_dirs: root.fallback_cache_dirs.clone(),
+            key_mode: root.key_mode,
+            artifacts: vec![],
+            disable_env_var: root.disable_env_var.clone(),
+        };
+        for file in &files {
+            // Braces in the directory of the file are literal, so they are escaped.
+            let prefix = match file.prefix.to_str() {
+                Some(prefix) => PathBuf::from(pattern::escape(prefix)),
+                None => file.prefix.clone(),
+            };
+            let prefixed = |paths: &[PathBuf]| -> Vec<PathBuf> {
+                paths.iter().map(|p| prefix.join(p)).collect()
+            };
+            let file_artifacts =
+                file.manifest
                     .artifacts
                     .iter()
                     .map(|(name, serde_arti)| Artifact {
-                        name: name.to_string(),
-                        inputs: serde_arti.inputs.clone(),
-                        outputs: serde_arti.outputs.clone(),
-                    })
-                    .collect(),
-                disable_env_var: serde_manifest.disable_env_var,
+                        name: file.artifact_name(name),
+                        inputs: prefixed(&serde_arti.inputs),
+                        outputs: prefixed(&serde_arti.outputs),
+                        clean: serde_arti.clean,
+                        special_files: serde_arti.special_files,
+                        symlinks: serde_arti.symlinks,
+                        values: serde_arti.values.clone(),
+                    });
+            manifest.artifacts.extend(file_artifacts);
+        }
+        Ok(manifest)
+    }
+
+    /// Check the Manifest file at `path` and all files it includes for problems and return all of
+    /// them.  `root_dir` is the root of the Git repository.
+    ///
+    /// In addition to syntax errors and unknown keys, this reports
+    /// - misplaced keys and includes that cannot be resolved,
+    /// - Artifacts that are defined more than once,
+    /// - absolute input and output paths,
+    /// - Artifact names that contain a wildcard more than once, paths that contain wildcards that
+    ///   are not in the name, and values for wildcards that are not in the name,
+    /// - outputs that are equal to, inside of, or contain an input of the same Artifact, and,
+    ///   if `repo` is given, inputs that are not tracked by Git.
+    ///
+    /// Returns an error only if the file at `path` cannot be read.
+    pub fn check(path: &Path, root_dir: &Path, repo: Option<&Repo>) -> Result<Vec<Issue>> {
+        Manifest::load(path, root_dir, repo).map(|(_, issues)| issues)
+    }
+
+    /// Load and validate the Manifest file at `path` and all files it includes.  The root Manifest
+    /// file is the first of the returned files.
+    fn load(
+        path: &Path,
+        root_dir: &Path,
+        repo: Option<&Repo>,
+    ) -> Result<(Vec<ManifestFile>, Vec<Issue>)> {
+        let path = fs::canonicalize(path)
+            .map_err(|cause| Error::chain(format!("Cannot open manifest {:?}!", path), cause))?;
+        let root_dir = fs::canonicalize(root_dir).map_err(|cause| {
+            Error::chain(format!("Cannot canonicalize path {:?}!", root_dir), cause)
+        })?;
+        let mut files = Vec::new();
+        let mut issues = Vec::new();
+        Manifest::load_file(
+            path.clone(),
+            PathBuf::new(),
+            vec![path],
+            &root_dir,
+            &mut HashSet::new(),
+            &mut files,
+            &mut issues,
+        )?;
+        let mut defined = HashSet::new();
+        for file in &files {
+            issues.extend(Manifest::validate(file, &mut defined, repo));
+        }
+        Ok((files, issues))
+    }
+
+    /// Load the Manifest file at `path` and, recursively, all files it includes.  `loaded` contains
+    /// the paths and prefixes of the files loaded so far, so that a file included more than once
+    /// (e.g., by two files that are both included by the root manifest) is loaded only once.
+    fn load_file(
+        path: PathBuf,
+        prefix: PathBuf,
+        chain: Vec<PathBuf>,
+        root_dir: &Path,
+        loaded: &mut HashSet<(PathBuf, PathBuf)>,
+        files: &mut Vec<ManifestFile>,
+        issues: &mut Vec<Issue>,
+    ) -> Result<()> {
+        if !loaded.insert((path.clone(), prefix.clone())) {
+            return Ok(());
+        }
+        let source = fs::read_to_string(&path)
+            .map_err(|cause| Error::chain(format!("Cannot open manifest {:?}!", path), cause))?;
+        let format = Format::from_path(&path);
+        let manifest = match format.parse(&source) {
+            Ok(manifest) => manifest,
+            Err((location, message)) => {
+                issues.push(Issue {
+                    path,
+                    location,
+                    message,
+                });
+                return Ok(());
+            }
+        };
+        let locations = match format {
+            // JSON is a subset of YAML, so the same parser determines positions.
+            Format::Yaml | Format::Json => Locations::parse(&source),
+            Format::Toml => Locations::parse_toml(&source),
+        };
+        let file = ManifestFile {
+            path,
+            prefix,
+            chain,
+            locations,
+            manifest,
+        };
+        let is_root = file.chain.len() == 1;
+        for key in &[
+            "cache_root_dir",
+            "fallback_cache_dirs",
+            "key_mode",
+            "disable_env_var",
+        ] {
+            if !is_root && file.locations.key(&[key], 0).is_some() {
+                issues.push(file.issue(
+                    file.locations.key(&[key], 0),
+                    format!("Key `{}` is only allowed in the root manifest.", key),
+                ));
+            }
+        }
+        let dir = file.path.parent().unwrap_or_else(|| Path::new("/"));
+        let mut includes = Vec::new();
+        for (idx, inc) in file.manifest.include.iter().enumerate() {
+            let loc = file.locations.value(&["include", &idx.to_string()]);
+            let inc_path = match dir.join(inc) {
+                p if p.is_dir() => {
+                    Manifest::find_in_dir(&p).unwrap_or_else(|| p.join("Memora.yml"))
+                }
+                p => p,
             };
-            // Add path of Manifest to inputs of each Artifact.
-            for artifact in &mut manifest.artifacts {
-                artifact.inputs.push(path.to_path_buf())
+            let inc_path = match fs::canonicalize(&inc_path) {
+                Ok(p) => p,
+                Err(_) => {
+                    issues.push(file.issue(
+                        loc,
+                        format!("Included manifest {:?} does not exist.", inc_path),
+                    ));
+                    continue;
+                }
+            };
+            if file.chain.contains(&inc_path) {
+                issues.push(file.issue(loc, format!("Manifest {:?} includes itself.", inc_path)));
+                continue;
             }
-            manifest
+            let inc_prefix = match inc_path.parent().map(|p| p.strip_prefix(root_dir)) {
+                Some(Ok(p)) => p.to_path_buf(),
+                _ => {
+                    issues.push(file.issue(
+                        loc,
+                        format!(
+                            "Included manifest {:?} is outside the repository.",
+                            inc_path
+                        ),
+                    ));
+                    continue;
+                }
+            };
+            let mut inc_chain = file.chain.clone();
+            inc_chain.insert(0, inc_path.clone());
+            includes.push((inc_path, inc_prefix, inc_chain));
+        }
+        files.push(file);
+        for (inc_path, inc_prefix, inc_chain) in includes {
+            Manifest::load_file(
+                inc_path, inc_prefix, inc_chain, root_dir, loaded, files, issues,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Validate the Artifacts of a Manifest file.  `defined` contains the names of the Artifacts
+    /// defined in previously validated files.
+    fn validate(
+        file: &ManifestFile,
+        defined: &mut HashSet<String>,
+        repo: Option<&Repo>,
+    ) -> Vec<Issue> {
+        let locations = &file.locations;
+        let mut issues = Vec::new();
+        let mut issue = |location: Option<Location>, message: String| {
+            issues.push(file.issue(location, message))
         };
-        Ok(manifest)
+        let mut occurrences: HashMap<&str, usize> = HashMap::new();
+        for (name, arti) in &file.manifest.artifacts {
+            let occurrence = {
+                let count = occurrences.entry(name).or_insert(0);
+                *count += 1;
+                *count - 1
+            };
+            let name_loc = locations.key(&["artifacts", name], occurrence);
+            let arti_elem = Locations::element(name, occurrence);
+            if !defined.insert(file.artifact_name(name)) {
+                issue(
+                    name_loc,
+                    format!(
+                        "Artifact \"{}\" is defined more than once.",
+                        file.artifact_name(name)
+                    ),
+                );
+            }
+            let name_wildcards = pattern::wildcards(name);
+            let mut repeated = Vec::new();
+            for (idx, wildcard) in name_wildcards.iter().enumerate() {
+                if name_wildcards[..idx].contains(wildcard) && !repeated.contains(&wildcard) {
+                    issue(
+                        name_loc,
+                        format!(
+                            "Artifact name \"{}\" contains wildcard `{}` more than once.",
+                            name,
+                            pattern::syntax(wildcard)
+                        ),
+                    );
+                    repeated.push(wildcard);
+                }
+            }
+            for key in arti.values.keys() {
+                if !name_wildcards.contains(key) {
+                    issue(
+                        locations.key(&["artifacts", &arti_elem, "values", key], 0),
+                        format!(
+                            "Artifact \"{}\" has values for `{}`, which is not a wildcard of its name.",
+                            name,
+                            pattern::syntax(key)
+                        ),
+                    );
+                }
+            }
+            if name.is_empty()
+                || Path::new(name)
+                    .components()
+                    .any(|c| !matches!(c, Component::Normal(_)))
+            {
+                issue(
+                    name_loc,
+                    format!(
+                        "Artifact name \"{}\" is empty or contains `.`, `..`, or a leading `/`.",
+                        name
+                    ),
+                );
+            }
+            for (kind, paths) in &[("inputs", &arti.inputs), ("outputs", &arti.outputs)] {
+                for (idx, p) in paths.iter().enumerate() {
+                    let loc = locations.value(&["artifacts", &arti_elem, kind, &idx.to_string()]);
+                    if p.is_absolute() {
+                        issue(
+                            loc,
+                            format!("Path {:?} of artifact \"{}\" is absolute.", p, name),
+                        );
+                    } else if resolve_path(&file.prefix.join(p)).starts_with("..") {
+                        issue(
+                            loc,
+                            format!(
+                                "Path {:?} of artifact \"{}\" is outside of the repository.",
+                                p, name
+                            ),
+                        );
+                    } else if p.components().any(|c| c == Component::ParentDir) {
+                        issue(
+                            loc,
+                            format!(
+                                "Path {:?} of artifact \"{}\" contains `..`, which could leave the repository through a symlink.",
+                                p, name
+                            ),
+                        );
+                    }
+                    let mut reported = Vec::new();
+                    for wildcard in pattern::wildcards(&p.to_string_lossy()) {
+                        if !name_wildcards.contains(&wildcard) && !reported.contains(&wildcard) {
+                            issue(
+                                loc,
+                                format!(
+                                    "Path {:?} of artifact \"{}\" contains wildcard `{}`, which is not in the artifact name.",
+                                    p,
+                                    name,
+                                    pattern::syntax(&wildcard)
+                                ),
+                            );
+                            reported.push(wildcard);
+                        }
+                    }
+                }
+            }
+            for (idx, oup) in arti.outputs.iter().enumerate() {
+                let oup_norm = normalize_path(oup);
+                for inp in &arti.inputs {
+                    let inp_norm = normalize_path(inp);
+                    if oup_norm.starts_with(&inp_norm) || inp_norm.starts_with(&oup_norm) {
+                        issue(
+                            locations.value(&[
+                                "artifacts",
+                                &arti_elem,
+                                "outputs",
+                                &idx.to_string(),
+                            ]),
+                            format!(
+                                "Output {:?} of artifact \"{}\" overlaps with input {:?}.",
+                                oup, name, inp
+                            ),
+                        );
+                    }
+                }
+            }
+            if let Some(repo) = repo {
+                for (idx, inp) in arti.inputs.iter().enumerate() {
+                    // Paths of Pattern Artifacts can only be checked once the pattern is matched.
+                    if !pattern::wildcards(&inp.to_string_lossy()).is_empty() || inp.is_absolute() {
+                        continue;
+                    }
+                    let path = pattern::instantiate(&inp.to_string_lossy(), &[]);
+                    if !repo.is_tracked(&file.prefix.join(path)) {
+                        issue(
+                            locations.value(&["artifacts", &arti_elem, "inputs", &idx.to_string()]),
+                            format!(
+                                "Input {:?} of artifact \"{}\" is not tracked by Git.",
+                                inp, name
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+        issues
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{create_file, write_file};
+    use tempdir::TempDir;
+
+    /// Create a temporary directory with the given files (relative path and content).
+    fn setup(files: &[(&str, &str)]) -> Result<TempDir> {
+        let tmp = TempDir::new("memora-test-config")
+            .map_err(|cause| Error::chain("Could not create temporary directory:", cause))?;
+        for (rel_path, source) in files {
+            let path = tmp.path().join(rel_path);
+            crate::fs::create_parents(&path)?;
+            let mut file = create_file(&path)?;
+            write_file(&mut file, source)?;
+        }
+        Ok(tmp)
+    }
+
+    fn check_str(source: &str) -> Result<Vec<Issue>> {
+        let tmp = setup(&[("Memora.yml", source)])?;
+        Manifest::check(&tmp.path().join("Memora.yml"), tmp.path(), None)
+    }
+
+    fn check_toml(source: &str) -> Result<Vec<Issue>> {
+        let tmp = setup(&[("Memora.toml", source)])?;
+        Manifest::check(&tmp.path().join("Memora.toml"), tmp.path(), None)
+    }
+
+    fn locations(issues: &[Issue]) -> Vec<(usize, usize)> {
+        issues
+            .iter()
+            .map(|issue| {
+                let loc = issue.location.expect("issue without location");
+                (loc.line, loc.column)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn valid_manifest() -> Result<()> {
+        let issues = check_str(
+            "cache_root_dir: /tmp\n\
+             key_mode: tree-hash\n\
+             artifacts:\n  \
+               foo:\n    \
+                 inputs: [a, b]\n    \
+                 outputs: [install/foo]\n    \
+                 clean: true\n    \
+                 special_files: skip\n    \
+                 symlinks: relative\n",
+        )?;
+        assert_eq!(issues, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_key() -> Result<()> {
+        let issues = check_str(
+            "cache_root_dir: /tmp\n\
+             artifacts:\n  \
+               foo:\n    \
+                 inputs: [a]\n    \
+                 output: [b]\n",
+        )?;
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].message.contains("unknown field `output`"));
+        assert_eq!(issues[0].location.unwrap().line, 5);
+        Ok(())
+    }
+
+    #[test]
+    fn all_problems_with_locations() -> Result<()> {
+        let issues = check_str(
+            "cache_root_dir: /tmp\n\
+             artifacts:\n  \
+               foo:\n    \
+                 inputs: [a]\n    \
+                 outputs: [b]\n  \
+               foo:\n    \
+                 inputs:\n      \
+                   - /abs\n    \
+                 outputs:\n      \
+                   - c\n  \
+               lib-%-%:\n    \
+                 inputs: [src]\n    \
+                 outputs: [src/lib]\n",
+        )?;
+        assert_eq!(issues.len(), 4, "{:#?}", issues);
+        assert_eq!(locations(&issues), vec![(6, 3), (8, 9), (11, 3), (13, 15)]);
+        Ok(())
+    }
+
+    #[test]
+    fn wildcards() -> Result<()> {
+        let issues = check_str(
+            "cache_root_dir: /tmp\n\
+             artifacts:\n  \
+               lib-{target}-{config}:\n    \
+                 inputs:\n      \
+                   - src/{target}\n    \
+                 outputs:\n      \
+                   - out/{target}/{config}/lib-{target}.a\n      \
+                   - out/{other}\n      \
+                   - out/{{literal}}\n    \
+                 values:\n      \
+                   target: [x86, arm]\n      \
+                   \"%\": [foo]\n  \
+               lib-{a}-{a}:\n    \
+                 inputs: [src]\n    \
+                 outputs: [out]\n",
+        )?;
+        assert_eq!(issues.len(), 3, "{:#?}", issues);
+        assert_eq!(locations(&issues), vec![(12, 7), (8, 9), (13, 3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn paths_outside_repository() -> Result<()> {
+        let issues = check_str(
+            "cache_root_dir: /tmp\n\
+             artifacts:\n  \
+               foo:\n    \
+                 inputs: [a/../b]\n    \
+                 outputs: [out/../../c]\n  \
+               ../bar:\n    \
+                 inputs: [d]\n    \
+                 outputs: [e]\n",
+        )?;
+        assert_eq!(locations(&issues), vec![(4, 14), (5, 15), (6, 3)]);
+        Ok(())
+    }
+
+    #[test]
+    fn include_namespaces_artifacts() -> Result<()> {
+        let tmp = setup(&[
+            (
+                ".ci/Memora.yml",
+                "cache_root_dir: /tmp\n\
+                 include: [../libs/foo]\n\
+                 artifacts:\n  \
+                   top:\n    \
+                     inputs: [src]\n    \
+                     outputs: [build]\n",
+            ),
+            (
+                "libs/foo/Memora.yml",
+                "artifacts:\n  \
+                   bar:\n    \
+                     inputs: [src]\n    \
+                     outputs: [build]\n",
+            ),
+        ])?;
+        let manifest = Manifest::from_path(&tmp.path().join(".ci/Memora.yml"), tmp.path())?;
+        assert_eq!(manifest.artifacts.len(), 2);
+        let top = &manifest.artifacts[0];
+        assert_eq!(top.name, "top");
+        assert_eq!(top.inputs, vec![PathBuf::from("src")]);
+        let bar = &manifest.artifacts[1];
+        assert_eq!(bar.name, "libs/foo/bar");
+        assert_eq!(bar.inputs, vec![PathBuf::from("libs/foo/src")]);
+        assert_eq!(bar.outputs, vec![PathBuf::from("libs/foo/build")]);
+        Ok(())
+    }
+
+    #[test]
+    fn include_diamond() -> Result<()> {
+        let tmp = setup(&[
+            (
+                "Memora.yml",
+                "cache_root_dir: /tmp\n\
+                 include: [b, c]\n",
+            ),
+            ("b/Memora.yml", "include: [../d]\n"),
+            ("c/Memora.yml", "include: [../d]\n"),
+            (
+                "d/Memora.yml",
+                "artifacts:\n  \
+                   foo:\n    \
+                     inputs: [src]\n    \
+                     outputs: [build]\n",
+            ),
+        ])?;
+        let issues = Manifest::check(&tmp.path().join("Memora.yml"), tmp.path(), None)?;
+        assert!(issues.is_empty(), "{:#?}", issues);
+        let manifest = Manifest::from_path(&tmp.path().join("Memora.yml"), tmp.path())?;
+        assert_eq!(manifest.artifacts.len(), 1);
+        assert_eq!(manifest.artifacts[0].name, "d/foo");
+        Ok(())
+    }
+
+    #[test]
+    fn include_problems() -> Result<()> {
+        let tmp = setup(&[
+            (
+                "Memora.yml",
+                "cache_root_dir: /tmp\n\
+                 include:\n  \
+                   - sub\n  \
+                   - nonexistent.yml\n",
+            ),
+            (
+                "sub/Memora.yml",
+                "cache_root_dir: /tmp\n\
+                 include: [../Memora.yml]\n",
+            ),
+        ])?;
+        let issues = Manifest::check(&tmp.path().join("Memora.yml"), tmp.path(), None)?;
+        assert_eq!(issues.len(), 3, "{:#?}", issues);
+        assert!(issues[0].message.contains("does not exist"));
+        assert_eq!(locations(&issues[..1]), vec![(4, 5)]);
+        assert!(issues[1]
+            .message
+            .contains("only allowed in the root manifest"));
+        assert!(issues[2].message.contains("includes itself"));
+        assert_eq!(locations(&issues[1..]), vec![(1, 1), (2, 11)]);
+        Ok(())
+    }
+
+    #[test]
+    fn toml_and_json_manifests() -> Result<()> {
+        let tmp = setup(&[
+            (
+                "Memora.toml",
+                "cache_root_dir = \"/tmp\"\n\
+                 include = [\"sub/Memora.json\"]\n\
+                 [artifacts.foo]\n\
+                 inputs = [\"a\"]\n\
+                 outputs = [\"b\"]\n",
+            ),
+            (
+                "sub/Memora.json",
+                "{\"artifacts\": {\"bar\": {\"inputs\": [\"a\"], \"outputs\": [\"a/b\"]}}}",
+            ),
+        ])?;
+        let issues = Manifest::check(&tmp.path().join("Memora.toml"), tmp.path(), None)?;
+        assert_eq!(issues.len(), 1, "{:#?}", issues);
+        assert!(issues[0].message.contains("overlaps with input"));
+        assert_eq!(locations(&issues), vec![(1, 53)]);
+        let issues = check_toml("cache_root_dir = \"/tmp\"\nartifact = 1\n")?;
+        assert_eq!(issues.len(), 1, "{:#?}", issues);
+        assert!(issues[0].message.contains("unknown field `artifact`"));
+        Ok(())
+    }
+
+    #[test]
+    fn toml_locations() -> Result<()> {
+        let tmp = setup(&[
+            (
+                "Memora.toml",
+                "cache_root_dir = \"/tmp\"\n\
+                 include = [\"sub\"]\n\
+                 [artifacts.foo]\n\
+                 inputs = [\"src\", \"/abs\"]\n\
+                 outputs = [\"src/out\"]\n\
+                 [artifacts]\n\
+                 bar = { inputs = [\"a\"], outputs = [\"b\"], values = { \"%\" = [\"x\"] } }\n",
+            ),
+            (
+                "sub/Memora.toml",
+                "key_mode = \"tree-hash\"\n\
+                 artifacts.baz.inputs = [\"a\"]\n\
+                 artifacts.baz.outputs = [\"../b\"]\n",
+            ),
+        ])?;
+        let issues = Manifest::check(&tmp.path().join("Memora.toml"), tmp.path(), None)?;
+        assert_eq!(issues.len(), 5, "{:#?}", issues);
+        assert_eq!(
+            locations(&issues),
+            vec![(1, 1), (4, 18), (5, 12), (7, 53), (3, 26)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn find_manifest() -> Result<()> {
+        let tmp = setup(&[(".github/Memora.json", "{}"), (".ci/Memora.toml", "")])?;
+        assert_eq!(
+            Manifest::find(tmp.path()),
+            Some(tmp.path().join(".ci/Memora.toml"))
+        );
+        let tmp = setup(&[(".github/Memora.json", "{}"), ("Memora.yml", "")])?;
+        assert_eq!(
+            Manifest::find(tmp.path()),
+            Some(tmp.path().join("Memora.yml"))
+        );
+        let tmp = setup(&[(".github/Memora.json", "{}")])?;
+        assert_eq!(
+            Manifest::find(tmp.path()),
+            Some(tmp.path().join(".github/Memora.json"))
+        );
+        Ok(())
     }
 }
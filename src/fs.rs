@@ -5,9 +5,12 @@
 //! Filesystem utilities
 
 use crate::error::{Error, Result};
-use log::{debug, trace};
+use crate::util::{relative_path, resolve_path};
+use log::{debug, trace, warn};
+use serde::Deserialize;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
 
 /// Recursively create a directory and all of its parent components if they are missing.
 pub fn create_dir<P: AsRef<Path>>(path: P) -> Result<()> {
@@ -27,25 +30,6 @@ pub fn file_type<P: AsRef<Path>>(path: P) -> Result<std::fs::FileType> {
     Ok(metadata.file_type())
 }
 
-/// Copy symlink without dereferencing it.  If the target already exists, it is overwritten.
-fn copy_symlink<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> Result<()> {
-    let from = from.as_ref();
-    let to = to.as_ref();
-    trace!("Copying symlink {:?} to {:?}.", from, to);
-    let link_target = std::fs::read_link(from).map_err(|cause| {
-        Error::chain(format!("Could not read source symlink {:?}:", from), cause)
-    })?;
-    // Remove `to` to prevent collisions if it exists.
-    let _foo = std::fs::remove_file(to);
-    std::os::unix::fs::symlink(link_target, to).map_err(|cause| {
-        Error::chain(
-            format!("Could not create destination symlink {:?}:", to),
-            cause,
-        )
-    })?;
-    Ok(())
-}
-
 /// Recursively create parent components to a path if they are missing.
 pub fn create_parents<P: AsRef<Path>>(path: P) -> Result<()> {
     let path = path.as_ref();
@@ -60,6 +44,491 @@ pub fn create_parents<P: AsRef<Path>>(path: P) -> Result<()> {
     create_dir(&path_parent)
 }
 
+/// Ensure that relative path `path` does not leave directory `root`, neither through `..`
+/// components nor through symlinks in the existing parent directories of `root.join(path)`.  The
+/// last component of `path` may be a symlink, which is handled by the symlink policies.
+///
+/// `path` must not contain `..` components at all: the OS resolves `link/..` to the parent of the
+/// target of `link` rather than to `root`, so `..` after a symlink could leave `root`.
+pub fn check_confined(root: &Path, path: &Path) -> Result<()> {
+    if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Error::result(format!(
+            "Path {:?} is absolute or contains `..`, which could leave {:?}!",
+            path, root
+        ));
+    }
+    let parent = match root.join(path).parent() {
+        Some(p) if resolve_path(path) != Path::new("") => p.to_path_buf(),
+        _ => return Ok(()),
+    };
+    let root = canonicalize(root)?;
+    if let Some(existing) = parent.ancestors().find(|p| p.exists()) {
+        if !canonicalize(existing)?.starts_with(&root) {
+            return Error::result(format!(
+                "Path {:?} leads outside of {:?} through a symlink!",
+                path, root
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// How symlinks are handled when copying.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Symlinks {
+    /// Copy symlinks verbatim.
+    #[default]
+    Preserve,
+    /// Fail on symlinks with an absolute target.
+    RejectAbsolute,
+    /// Rewrite absolute targets to targets relative to the symlink.
+    Relative,
+    /// Copy the targets of symlinks instead of the symlinks.  Dangling symlinks are an error.
+    Dereference,
+}
+
+/// Resolve absolute path `path` like the OS does, following the symlinks in all of its existing
+/// components.  A `..` that follows a component that does not exist (yet) is an error, because that
+/// component could later be created as a symlink.
+fn resolve_symlinks(path: &Path) -> Result<PathBuf> {
+    // Components that remain to be resolved, in reverse order.
+    let mut pending: Vec<PathBuf> = path
+        .components()
+        .rev()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect();
+    let mut resolved = PathBuf::from("/");
+    let mut links = 0;
+    while let Some(component) = pending.pop() {
+        match component.components().next() {
+            Some(Component::RootDir) => resolved = PathBuf::from("/"),
+            Some(Component::Normal(_)) => {
+                let next = resolved.join(&component);
+                match fs::read_link(&next) {
+                    Ok(target) => {
+                        links += 1;
+                        if links > 40 {
+                            return Error::result(format!(
+                                "Too many levels of symlinks in {:?}!",
+                                path
+                            ));
+                        }
+                        pending.extend(
+                            target
+                                .components()
+                                .rev()
+                                .map(|c| PathBuf::from(c.as_os_str())),
+                        );
+                    }
+                    Err(_) => resolved = next,
+                }
+            }
+            Some(Component::ParentDir) => {
+                if !resolved.is_dir() {
+                    return Error::result(format!(
+                        "Cannot resolve `..` after {:?}, which does not exist, in {:?}!",
+                        resolved, path
+                    ));
+                }
+                resolved.pop();
+            }
+            _ => (),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Canonicalize path `path`.
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    path.canonicalize()
+        .map_err(|cause| Error::chain(format!("Could not canonicalize {:?}:", path), cause))
+}
+
+/// Copy symlink `from` to `to` according to `options.symlinks` (which must not be `Dereference`),
+/// `options.relative_root`, and `options.confine_symlinks`.
+fn transfer_symlink(from: &Path, to: &Path, options: &CopyOptions) -> Result<()> {
+    let target = fs::read_link(from).map_err(|cause| {
+        Error::chain(format!("Could not read source symlink {:?}:", from), cause)
+    })?;
+    let target = match options.symlinks {
+        Symlinks::RejectAbsolute if target.is_absolute() => {
+            return Error::result(format!(
+                "Symlink {:?} has absolute target {:?}!",
+                from, target
+            ));
+        }
+        Symlinks::Relative if target.is_absolute() => {
+            let dir = resolve_symlinks(from.parent().unwrap_or_else(|| Path::new("/")))?;
+            let resolved = resolve_symlinks(&target)?;
+            if let Some(root) = &options.relative_root {
+                if !resolved.starts_with(canonicalize(root)?) {
+                    return Error::result(format!(
+                        "Symlink {:?} has absolute target {:?}, which is outside of {:?}!",
+                        from, target, root
+                    ));
+                }
+            }
+            let relative = relative_path(&resolved, &dir);
+            trace!(
+                "Rewriting target of symlink {:?} from {:?} to {:?}.",
+                from,
+                target,
+                relative
+            );
+            relative
+        }
+        _ => target,
+    };
+    if let Some(root) = &options.confine_symlinks {
+        let dir = to.parent().unwrap_or_else(|| Path::new("/"));
+        let confined = resolve_symlinks(&dir.join(&target))
+            .and_then(|resolved| Ok(resolved.starts_with(canonicalize(root)?)));
+        if !matches!(confined, Ok(true)) {
+            return Error::result(format!(
+                "Refusing to create symlink {:?} to {:?}, which is (or may be) outside of {:?}!",
+                to, target, root
+            ));
+        }
+    }
+    trace!("Creating symlink {:?} to {:?}.", to, target);
+    // Remove `to` to prevent collisions if it exists.
+    remove_file_if_exists(to)?;
+    std::os::unix::fs::symlink(&target, to).map_err(|cause| {
+        Error::chain(
+            format!("Could not create destination symlink {:?}:", to),
+            cause,
+        )
+    })
+}
+
+/// How special files (FIFOs, sockets, and device nodes) are handled when copying.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum SpecialFiles {
+    /// Skip special files with a warning.
+    Skip,
+    /// Recreate special files with the same type, permissions, and device number.  Creating device
+    /// nodes usually requires privileges.
+    Recreate,
+    /// Fail on special files.
+    #[default]
+    Fail,
+}
+
+/// Recreate special file `from` at `to`.
+fn recreate_special_file(from: &Path, to: &Path) -> Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    trace!("Recreating special file {:?} at {:?}.", from, to);
+    let metadata = from
+        .symlink_metadata()
+        .map_err(|cause| Error::chain(format!("Could not get metadata of {:?}:", from), cause))?;
+    remove_file_if_exists(to)?;
+    let c_to = CString::new(to.as_os_str().as_bytes())
+        .map_err(|cause| Error::chain(format!("Invalid path {:?}:", to), cause))?;
+    // SAFETY: `c_to` is a valid C string.
+    let ret = unsafe {
+        libc::mknod(
+            c_to.as_ptr(),
+            metadata.mode() as libc::mode_t,
+            metadata.rdev() as libc::dev_t,
+        )
+    };
+    if ret != 0 {
+        return Err(Error::chain(
+            format!("Could not recreate special file {:?} at {:?}:", from, to),
+            std::io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// Copy file `from` to `to` like `std::fs::copy`, but preserve the holes of sparse files.
+fn copy_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = from.metadata()?;
+    if metadata.blocks() * 512 < metadata.len() {
+        copy_sparse_file(from, to, &metadata)
+    } else {
+        fs::copy(from, to).map(|_| ())
+    }
+}
+
+/// Copy the data segments of sparse file `from` to `to`, leaving holes where `from` has holes.
+#[cfg(target_os = "linux")]
+fn copy_sparse_file(from: &Path, to: &Path, metadata: &fs::Metadata) -> std::io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+    trace!("Copying sparse file {:?} to {:?}.", from, to);
+    let mut src = fs::File::open(from)?;
+    let mut dst = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(to)?;
+    let len = metadata.len() as libc::off_t;
+    let mut offset: libc::off_t = 0;
+    while offset < len {
+        // SAFETY: The file descriptor is valid for the lifetime of `src`.
+        let data = unsafe { libc::lseek(src.as_raw_fd(), offset, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                // There is no data after `offset`.
+                break;
+            }
+            return Err(e);
+        }
+        let hole = unsafe { libc::lseek(src.as_raw_fd(), data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        src.seek(SeekFrom::Start(data as u64))?;
+        dst.seek(SeekFrom::Start(data as u64))?;
+        std::io::copy(&mut (&mut src).take((hole - data) as u64), &mut dst)?;
+        offset = hole;
+    }
+    dst.set_len(metadata.len())?;
+    fs::set_permissions(to, metadata.permissions())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_sparse_file(from: &Path, to: &Path, _metadata: &fs::Metadata) -> std::io::Result<()> {
+    fs::copy(from, to).map(|_| ())
+}
+
+/// The modification time that copied files get.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mtime {
+    /// The time of copying.
+    #[default]
+    Touch,
+    /// The modification time of the original file.
+    Preserve,
+    /// The contained time.
+    Set(SystemTime),
+}
+
+/// Options for copying files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyOptions {
+    /// The modification time of copied files.
+    pub mtime: Mtime,
+    /// Preserve the owner and group.  If they cannot be preserved (e.g., because this process is
+    /// not privileged), a warning is logged.
+    pub ownership: bool,
+    /// Preserve the extended attributes.
+    pub xattrs: bool,
+    /// Number of threads that copy files.  If zero, as many threads as the machine can run in
+    /// parallel are used.
+    pub threads: usize,
+    /// How special files are handled.
+    pub special_files: SpecialFiles,
+    /// How symlinks are handled.
+    pub symlinks: Symlinks,
+    /// If set, `Symlinks::Relative` rewrites only absolute targets inside of this directory, and
+    /// other absolute targets are an error.
+    pub relative_root: Option<PathBuf>,
+    /// If set, creating a symlink whose target (resolved by following existing symlinks) is
+    /// outside of this directory is an error.
+    pub confine_symlinks: Option<PathBuf>,
+}
+
+impl Default for CopyOptions {
+    fn default() -> CopyOptions {
+        CopyOptions {
+            mtime: Mtime::default(),
+            ownership: false,
+            xattrs: false,
+            threads: 1,
+            special_files: SpecialFiles::default(),
+            symlinks: Symlinks::default(),
+            relative_root: None,
+            confine_symlinks: None,
+        }
+    }
+}
+
+/// Apply the metadata of file `from` selected by `options` to file `to`.
+fn apply_metadata(from: &Path, to: &Path, options: &CopyOptions) -> Result<()> {
+    if options.ownership {
+        use std::os::unix::fs::MetadataExt;
+        let metadata = from.symlink_metadata().map_err(|cause| {
+            Error::chain(format!("Could not get metadata of {:?}:", from), cause)
+        })?;
+        if let Err(e) = std::os::unix::fs::lchown(to, Some(metadata.uid()), Some(metadata.gid())) {
+            warn!("Could not preserve ownership of {:?}: {}", to, e);
+        }
+    }
+    if options.xattrs {
+        copy_xattrs(from, to).map_err(|cause| {
+            Error::chain(
+                format!(
+                    "Could not copy extended attributes of {:?} to {:?}:",
+                    from, to
+                ),
+                cause,
+            )
+        })?;
+    }
+    let mtime = match options.mtime {
+        Mtime::Touch => return Ok(()),
+        Mtime::Preserve => from
+            .metadata()
+            .and_then(|m| m.modified())
+            .map_err(|cause| {
+                Error::chain(
+                    format!("Could not get modification time of {:?}:", from),
+                    cause,
+                )
+            })?,
+        Mtime::Set(time) => time,
+    };
+    set_mtime(to, mtime)
+}
+
+/// Set the modification time of a file.
+pub fn set_mtime(path: &Path, mtime: SystemTime) -> Result<()> {
+    trace!("Setting modification time of {:?} to {:?}.", path, mtime);
+    // Setting the times of a file does not require it to be opened for writing.
+    fs::File::open(path)
+        .and_then(|file| file.set_times(fs::FileTimes::new().set_modified(mtime)))
+        .map_err(|cause| {
+            Error::chain(
+                format!("Could not set modification time of {:?}:", path),
+                cause,
+            )
+        })
+}
+
+/// Return the oldest modification time of the files under `paths` (without following symlinks),
+/// or `None` if there are no files.
+pub fn oldest_mtime<P: AsRef<Path>>(paths: &[P]) -> Result<Option<SystemTime>> {
+    let mut oldest = None;
+    for path in paths {
+        for entry in walkdir::WalkDir::new(path.as_ref()).follow_links(false) {
+            let entry = entry.map_err(|cause| {
+                Error::chain(format!("Cannot walk {:?}:", path.as_ref()), cause)
+            })?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let mtime = entry
+                .metadata()
+                .map_err(|cause| {
+                    Error::chain(
+                        format!("Could not get metadata of {:?}:", entry.path()),
+                        cause,
+                    )
+                })?
+                .modified()
+                .map_err(|cause| {
+                    Error::chain(
+                        format!("Could not get modification time of {:?}:", entry.path()),
+                        cause,
+                    )
+                })?;
+            if oldest.map_or(true, |oldest| mtime < oldest) {
+                oldest = Some(mtime);
+            }
+        }
+    }
+    Ok(oldest)
+}
+
+/// Copy the extended attributes of `from` to `to` (without following symlinks).
+///
+/// Attributes that are not supported by the file system of `to` (e.g., tmpfs or NFS) or that this
+/// process may not read or write (e.g., `trusted.*` without privileges) are skipped with a
+/// warning; other errors are returned.
+#[cfg(target_os = "linux")]
+fn copy_xattrs(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+    };
+    let is_skippable =
+        |e: &std::io::Error| matches!(e.raw_os_error(), Some(libc::ENOTSUP) | Some(libc::EPERM));
+    let (from_path, to_path) = (from, to);
+    let (from, to) = (c_path(from)?, c_path(to)?);
+    // Query the required buffer size, then read the list of attribute names.
+    // SAFETY: The paths are valid C strings, and each buffer is valid for the given length.
+    let size = unsafe { libc::llistxattr(from.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let e = std::io::Error::last_os_error();
+        if is_skippable(&e) {
+            // The file system of `from` has no extended attributes.
+            return Ok(());
+        }
+        return Err(e);
+    }
+    let mut names = vec![0u8; size as usize];
+    let size =
+        unsafe { libc::llistxattr(from.as_ptr(), names.as_mut_ptr() as *mut _, names.len()) };
+    if size < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    names.truncate(size as usize);
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let name = CString::new(name).unwrap();
+        let copy_value = || {
+            let size =
+                unsafe { libc::lgetxattr(from.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+            if size < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mut value = vec![0u8; size as usize];
+            let size = unsafe {
+                libc::lgetxattr(
+                    from.as_ptr(),
+                    name.as_ptr(),
+                    value.as_mut_ptr() as *mut _,
+                    value.len(),
+                )
+            };
+            if size < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let ret = unsafe {
+                libc::lsetxattr(
+                    to.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const _,
+                    size as usize,
+                    0,
+                )
+            };
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        };
+        match copy_value() {
+            Err(e) if is_skippable(&e) => warn!(
+                "Skipping extended attribute {:?} of {:?} for {:?}: {}",
+                name, from_path, to_path, e
+            ),
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Extended attributes are only copied on Linux.  Elsewhere, they are skipped with a warning (once
+/// per process), like attributes that a file system does not support.
+#[cfg(not(target_os = "linux"))]
+fn copy_xattrs(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    WARNED.call_once(|| {
+        warn!("Extended attributes are not supported on this platform; not preserving them.")
+    });
+    Ok(())
+}
+
 /// Recursively copy path `from` to path `to`.
 ///
 /// - `to` must not be a subpath of `from`.
@@ -68,6 +537,16 @@ pub fn create_parents<P: AsRef<Path>>(path: P) -> Result<()> {
 /// - Files that exist under `to` and under `from` are overwritten with the file under `from`.
 /// - Files that exist under `to` but not under `from` are not touched.
 pub fn copy<P, Q>(from: P, to: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    copy_with_options(from, to, &CopyOptions::default())
+}
+
+/// Recursively copy path `from` to path `to` like [`copy`](fn.copy.html), preserving the metadata
+/// of files and using the number of threads selected by `options`.
+pub fn copy_with_options<P, Q>(from: P, to: Q, options: &CopyOptions) -> Result<()>
 where
     P: AsRef<Path>,
     Q: AsRef<Path>,
@@ -75,55 +554,371 @@ where
     let from = from.as_ref();
     let to = to.as_ref();
     debug!("Copying {:?} to {:?}.", from, to);
+    transfer(from, to, options, |from, to| {
+        copy_file(from, to).map_err(|cause| {
+            Error::chain(
+                format!("Could not copy file {:?} to {:?}!", from, to),
+                cause,
+            )
+        })?;
+        apply_metadata(from, to, options)
+    })
+}
+
+/// Recursively mirror path `from` at path `to`.  Directories are created, symlinks are copied, and
+/// special files are handled according to `options.special_files` while walking `from`; each
+/// regular file is then passed to `transfer_file` together with its path under `to`, on up to
+/// `options.threads` threads.
+fn transfer<F>(from: &Path, to: &Path, options: &CopyOptions, transfer_file: F) -> Result<()>
+where
+    F: Fn(&Path, &Path) -> Result<()> + Sync,
+{
+    let dereference = options.symlinks == Symlinks::Dereference;
     // The case when `from` itself is a symlink needs to be handled specially because `WalkDir`
     // always dereferences the given (top-level) path.
-    if file_type(from)?.is_symlink() {
+    if !dereference && file_type(from)?.is_symlink() {
         trace!("From path is a symlink.");
         create_parents(to)?;
-        copy_symlink(from, to)?;
-    } else {
-        for entry in walkdir::WalkDir::new(from).follow_links(false) {
-            // Determine path to entry.
-            let entry_path = entry
-                .as_ref()
-                .map_err(|_cause| Error::new(format!("Cannot handle filesystem entry {:?}", entry)))
-                .map(|entry| entry.path())?;
-            // Determine relative path of *from* entry.
-            let relative_from = entry_path.strip_prefix(from).map_err(|cause| {
-                Error::chain(format!("Cannot relativize path {:?}:", entry_path), cause)
-            })?;
-            let from = entry_path;
-            // Determine absolute path of *to* entry.
-            let to = if relative_from != Path::new("") {
-                to.join(relative_from)
-            } else {
-                PathBuf::from(to)
-            };
-            trace!("Copying {:?} to {:?}.", from, to);
-            // Create parent directory of `to` (if it does not exist).
-            create_parents(&to)?;
-            // Query metadata (without following symlinks).
-            let filetype = file_type(&from)?;
-            if filetype.is_file() {
-                // Copy `from` file using standard `fs::copy`.
-                fs::copy(&from, &to).map_err(|cause| {
-                    Error::chain(
-                        format!("Could not copy file {:?} to {:?}!", from, to),
-                        cause,
-                    )
-                })?;
-            } else if filetype.is_dir() {
-                create_dir(&to)?;
-            } else if filetype.is_symlink() {
-                copy_symlink(&from, &to)?;
-            } else {
-                Error::result(format!("Can not copy file type {:?}", filetype))?;
+        return transfer_symlink(from, to, options);
+    }
+    let mut files = Vec::new();
+    for entry in walkdir::WalkDir::new(from).follow_links(dereference) {
+        // Determine path to entry.
+        let entry_path = entry
+            .as_ref()
+            .map_err(|_cause| Error::new(format!("Cannot handle filesystem entry {:?}", entry)))
+            .map(|entry| entry.path())?;
+        // Determine relative path of *from* entry.
+        let relative_from = entry_path.strip_prefix(from).map_err(|cause| {
+            Error::chain(format!("Cannot relativize path {:?}:", entry_path), cause)
+        })?;
+        let from = entry_path;
+        // Determine absolute path of *to* entry.
+        let to = if relative_from != Path::new("") {
+            to.join(relative_from)
+        } else {
+            PathBuf::from(to)
+        };
+        trace!("Copying {:?} to {:?}.", from, to);
+        // Create parent directory of `to` (if it does not exist).
+        create_parents(&to)?;
+        // Query metadata (following symlinks only if they are dereferenced).
+        let filetype = match dereference {
+            false => file_type(from)?,
+            true => from
+                .metadata()
+                .map_err(|cause| {
+                    Error::chain(format!("Could not get metadata of {:?}:", from), cause)
+                })?
+                .file_type(),
+        };
+        if filetype.is_file() {
+            files.push((from.to_path_buf(), to));
+        } else if filetype.is_dir() {
+            create_dir(&to)?;
+        } else if filetype.is_symlink() {
+            transfer_symlink(from, &to, options)?;
+        } else {
+            match options.special_files {
+                SpecialFiles::Skip => warn!("Skipping special file {:?}.", from),
+                SpecialFiles::Recreate => recreate_special_file(from, &to)?,
+                SpecialFiles::Fail => {
+                    Error::result(format!("Can not copy file type {:?}", filetype))?
+                }
             }
         }
     }
+    for_each_parallel(&files, options.threads, |(from, to)| {
+        transfer_file(from, to)
+    })
+}
+
+/// Apply `f` to each item on up to `threads` threads (or as many threads as the machine can run in
+/// parallel if `threads` is zero).  After the first error, no further items are started, and that
+/// error is returned.
+fn for_each_parallel<T, F>(items: &[T], threads: usize, f: F) -> Result<()>
+where
+    T: Sync,
+    F: Fn(&T) -> Result<()> + Sync,
+{
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    let threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+    .min(items.len());
+    if threads <= 1 {
+        return items.iter().try_for_each(f);
+    }
+    trace!("Processing {} items on {} threads.", items.len(), threads);
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let first_error = Mutex::new(None);
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                while !failed.load(Ordering::Relaxed) {
+                    let item = match items.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    if let Err(e) = f(item) {
+                        failed.store(true, Ordering::Relaxed);
+                        first_error.lock().unwrap().get_or_insert(e);
+                    }
+                }
+            });
+        }
+    });
+    match first_error.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Remove the paths under `to` that do not exist under `from` or have a different file type, so
+/// that copying or linking `from` to `to` afterwards makes `to` mirror `from` exactly.  Symlinks are
+/// not followed.
+pub fn prune<P, Q>(from: P, to: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let from = from.as_ref();
+    let to = to.as_ref();
+    if to.symlink_metadata().is_err() {
+        return Ok(());
+    }
+    debug!(
+        "Removing paths under {:?} that are not under {:?}.",
+        to, from
+    );
+    let mut walker = walkdir::WalkDir::new(to).follow_links(false).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = entry.map_err(|cause| Error::chain(format!("Cannot walk {:?}:", to), cause))?;
+        let relative = entry.path().strip_prefix(to).map_err(|cause| {
+            Error::chain(format!("Cannot relativize path {:?}:", entry.path()), cause)
+        })?;
+        let same_type = match file_type(from.join(relative)) {
+            Ok(t) => {
+                let u = entry.file_type();
+                t.is_dir() == u.is_dir()
+                    && t.is_file() == u.is_file()
+                    && t.is_symlink() == u.is_symlink()
+            }
+            Err(_) => false,
+        };
+        if same_type {
+            continue;
+        }
+        trace!("Removing {:?}.", entry.path());
+        if entry.file_type().is_dir() {
+            walker.skip_current_dir();
+            fs::remove_dir_all(entry.path())
+        } else {
+            fs::remove_file(entry.path())
+        }
+        .map_err(|cause| Error::chain(format!("Could not remove {:?}:", entry.path()), cause))?;
+    }
+    Ok(())
+}
+
+/// How files are transferred from the cache to the repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkMode {
+    /// Copy files.
+    #[default]
+    Copy,
+    /// Create hard links to the files in the cache.
+    Hardlink,
+    /// Clone files with copy-on-write (if supported by the file system).
+    Reflink,
+    /// Create symbolic links to the files in the cache.
+    Symlink,
+}
+
+impl std::fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkMode::Copy => write!(f, "copy"),
+            LinkMode::Hardlink => write!(f, "hardlink"),
+            LinkMode::Reflink => write!(f, "reflink"),
+            LinkMode::Symlink => write!(f, "symlink"),
+        }
+    }
+}
+
+impl std::str::FromStr for LinkMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<LinkMode> {
+        match s {
+            "copy" => Ok(LinkMode::Copy),
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "reflink" => Ok(LinkMode::Reflink),
+            "symlink" => Ok(LinkMode::Symlink),
+            _ => Error::result(format!("Unknown link mode \"{}\"!", s)),
+        }
+    }
+}
+
+/// Remove the write permissions of all files under `path` (without following symlinks), so they
+/// cannot be modified through hard or symbolic links.
+pub fn write_protect<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    trace!("Write-protecting {:?}.", path);
+    for entry in walkdir::WalkDir::new(path).follow_links(false) {
+        let entry =
+            entry.map_err(|cause| Error::chain(format!("Cannot walk {:?}:", path), cause))?;
+        if entry.file_type().is_file() {
+            write_protect_file(entry.path())?;
+        }
+    }
     Ok(())
 }
 
+fn write_protect_file(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = path
+        .metadata()
+        .map_err(|cause| Error::chain(format!("Could not get metadata of {:?}:", path), cause))?
+        .permissions();
+    if permissions.mode() & 0o222 != 0 {
+        permissions.set_mode(permissions.mode() & !0o222);
+        fs::set_permissions(path, permissions)
+            .map_err(|cause| Error::chain(format!("Could not write-protect {:?}:", path), cause))?;
+    }
+    Ok(())
+}
+
+/// Fail unless `path` is write-protected, so it cannot be modified through hard or symbolic links.
+fn check_write_protected(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = path
+        .metadata()
+        .map_err(|cause| Error::chain(format!("Could not get metadata of {:?}:", path), cause))?
+        .permissions()
+        .mode();
+    match mode & 0o222 {
+        0 => Ok(()),
+        _ => Error::result(format!("{:?} is not write-protected.", path)),
+    }
+}
+
+/// Remove `path` if it exists as a file or symlink, so that it is replaced instead of written
+/// through (which would modify the original of a hard or symbolic link).
+fn remove_file_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(Error::chain(format!("Could not remove {:?}:", path), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Copy a file out of the cache, making the copy writable by its owner.
+fn copy_file_writable(from: &Path, to: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    copy_file(from, to).map_err(|cause| {
+        Error::chain(
+            format!("Could not copy file {:?} to {:?}!", from, to),
+            cause,
+        )
+    })?;
+    let mut permissions = to
+        .metadata()
+        .map_err(|cause| Error::chain(format!("Could not get metadata of {:?}:", to), cause))?
+        .permissions();
+    permissions.set_mode(permissions.mode() | 0o200);
+    fs::set_permissions(to, permissions)
+        .map_err(|cause| Error::chain(format!("Could not make {:?} writable:", to), cause))
+}
+
+/// Clone file `from` to `to` with copy-on-write.
+#[cfg(target_os = "linux")]
+fn reflink_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    /// `_IOW(0x94, 9, int)` from `linux/fs.h`.
+    const FICLONE: u64 = 0x4004_9409;
+    let src = fs::File::open(from)?;
+    let dst = fs::File::create(to)?;
+    // SAFETY: Both file descriptors are valid for the duration of the call.
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    if ret == 0 {
+        fs::set_permissions(to, src.metadata()?.permissions())
+    } else {
+        let e = std::io::Error::last_os_error();
+        drop(dst);
+        let _ = fs::remove_file(to);
+        Err(e)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink_file(_from: &Path, _to: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "reflinks are not supported on this platform",
+    ))
+}
+
+/// Transfer a file from the cache to the repository with a link mode, falling back to copying.
+/// Returns whether `to` is a separate file (i.e., not a hard or symbolic link to `from`).
+fn link_file(from: &Path, to: &Path, mode: LinkMode) -> Result<bool> {
+    remove_file_if_exists(to)?;
+    let linked = match mode {
+        LinkMode::Copy => return copy_file_writable(from, to).map(|()| true),
+        LinkMode::Hardlink => check_write_protected(from).and_then(|()| {
+            fs::hard_link(from, to)
+                .map_err(|cause| Error::chain(format!("Could not hard-link {:?}:", from), cause))
+        }),
+        LinkMode::Reflink => reflink_file(from, to)
+            .map_err(|cause| Error::chain(format!("Could not reflink {:?}:", from), cause)),
+        LinkMode::Symlink => check_write_protected(from).and_then(|()| {
+            std::os::unix::fs::symlink(from, to)
+                .map_err(|cause| Error::chain(format!("Could not symlink {:?}:", from), cause))
+        }),
+    };
+    match linked {
+        Ok(()) => Ok(mode == LinkMode::Reflink),
+        Err(e) => {
+            debug!("{} Falling back to copying.", e);
+            copy_file_writable(from, to).map(|()| true)
+        }
+    }
+}
+
+/// Recursively transfer path `from` in the cache to path `to` in the repository with a link mode.
+///
+/// This behaves like [`copy`](fn.copy.html), except that
+/// - files are transferred with `mode`, falling back to copying where that is not possible (e.g.,
+///   hard links across file systems or reflinks on file systems without copy-on-write),
+/// - hard or symbolic links are only created to files in the cache that are write-protected (as
+///   entries are on insertion); other files are copied, since `from` is only locked for reading,
+/// - existing files under `to` are replaced instead of overwritten, so a hard or symbolic link from
+///   an earlier transfer never modifies the cache,
+/// - files transferred by copying are writable by their owner, and
+/// - the metadata selected by `options` is applied to all files that are not hard or symbolic
+///   links to the cache (hard links share their metadata with the file in the cache).
+pub fn link<P, Q>(from: P, to: Q, mode: LinkMode, options: &CopyOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let from = from.as_ref();
+    let to = to.as_ref();
+    debug!(
+        "Transferring {:?} to {:?} with link mode {}.",
+        from, to, mode
+    );
+    transfer(from, to, options, |from, to| {
+        if link_file(from, to, mode)? {
+            apply_metadata(from, to, options)?;
+        }
+        Ok(())
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -247,4 +1042,349 @@ mod tests {
         assert_eq!(dst_symlink.read_link().unwrap(), nonexisting);
         Ok(())
     }
+
+    /// Set up a write-protected source file and a destination that is a hard link to it.
+    fn setup_linked_file() -> Result<(TempDir, TempDir, PathBuf, PathBuf)> {
+        let (src_dir, dst_dir, src_path, dst_path) = setup_single_file(Path::new("some_file"))?;
+        write_protect(&src_path)?;
+        std::fs::hard_link(&src_path, &dst_path).unwrap();
+        Ok((src_dir, dst_dir, src_path, dst_path))
+    }
+
+    #[test]
+    /// Link a file with each link mode over an existing hard link to it.
+    fn link_file_modes() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        for mode in &[
+            LinkMode::Copy,
+            LinkMode::Hardlink,
+            LinkMode::Reflink,
+            LinkMode::Symlink,
+        ] {
+            let (_src_dir, _dst_dir, src_path, dst_path) = setup_linked_file()?;
+            link(&src_path, &dst_path, *mode, &CopyOptions::default())?;
+            diff(&src_path, &dst_path)?;
+            let same_inode =
+                src_path.metadata().unwrap().ino() == dst_path.metadata().unwrap().ino();
+            assert_eq!(
+                same_inode,
+                *mode == LinkMode::Hardlink || *mode == LinkMode::Symlink
+            );
+            assert_eq!(
+                file_type(&dst_path)?.is_symlink(),
+                *mode == LinkMode::Symlink
+            );
+            assert!(src_path.metadata().unwrap().permissions().readonly());
+            if !same_inode {
+                assert!(!dst_path.metadata().unwrap().permissions().readonly());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Hard-link a directory whose files are not write-protected, which copies them instead.
+    fn link_unprotected_dir_copies() -> Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        let (src_dir, dst_dir, src_file, dst_file) =
+            setup_single_file(Path::new("some/subdir/file"))?;
+        link(
+            src_dir.path().join("some"),
+            dst_dir.path().join("some"),
+            LinkMode::Hardlink,
+            &CopyOptions::default(),
+        )?;
+        diff(&src_file, &dst_file)?;
+        assert!(!src_file.metadata().unwrap().permissions().readonly());
+        assert_ne!(
+            src_file.metadata().unwrap().ino(),
+            dst_file.metadata().unwrap().ino()
+        );
+        Ok(())
+    }
+
+    #[test]
+    /// Copy and link files preserving or setting their modification time.
+    fn copy_and_link_mtimes() -> Result<()> {
+        let (_src_dir, _dst_dir, src_path, dst_path) = setup_single_file(Path::new("some_file"))?;
+        let past = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        set_mtime(&src_path, past)?;
+        let mtime = |p: &Path| p.metadata().unwrap().modified().unwrap();
+        copy(&src_path, &dst_path)?;
+        assert_ne!(mtime(&dst_path), past);
+        let preserve = CopyOptions {
+            mtime: Mtime::Preserve,
+            ..CopyOptions::default()
+        };
+        copy_with_options(&src_path, &dst_path, &preserve)?;
+        assert_eq!(mtime(&dst_path), past);
+        let older = past - std::time::Duration::from_secs(1);
+        let set = CopyOptions {
+            mtime: Mtime::Set(older),
+            ..CopyOptions::default()
+        };
+        link(&src_path, &dst_path, LinkMode::Copy, &set)?;
+        assert_eq!(mtime(&dst_path), older);
+        // The modification time of a hard link is that of the file in the cache.
+        link(&src_path, &dst_path, LinkMode::Hardlink, &set)?;
+        assert_eq!(mtime(&src_path), past);
+        assert_eq!(oldest_mtime(&[&src_path])?, Some(past));
+        Ok(())
+    }
+
+    #[test]
+    /// Prune a directory with extraneous files and a path of a different file type.
+    fn prune_dir() -> Result<()> {
+        let (src_dir, dst_dir, src_file, dst_file) =
+            setup_single_file(Path::new("some/subdir/file"))?;
+        let src_path = src_dir.path().join("some");
+        let dst_path = dst_dir.path().join("some");
+        create_dir(src_path.join("other"))?;
+        create_file(src_path.join("type"))?;
+        copy(&src_path, &dst_path)?;
+        create_file(dst_path.join("subdir/stale"))?;
+        create_dir(dst_path.join("stale/dir"))?;
+        std::fs::remove_file(dst_path.join("type")).unwrap();
+        create_dir(dst_path.join("type"))?;
+        prune(&src_path, &dst_path)?;
+        assert!(!dst_path.join("subdir/stale").exists());
+        assert!(!dst_path.join("stale").exists());
+        assert!(!dst_path.join("type").exists());
+        assert!(dst_path.join("other").is_dir());
+        diff(&src_file, &dst_file)
+    }
+
+    #[test]
+    /// Copy and link a directory with many files on multiple threads.
+    fn copy_and_link_parallel() -> Result<()> {
+        let (src_dir, dst_dir) = setup()?;
+        let src_path = src_dir.path().join("some");
+        for i in 0..50 {
+            let path = src_path.join(format!("dir{}/file{}", i % 7, i));
+            create_parents(&path)?;
+            write_file(&mut create_file(&path)?, &format!("content {}", i))?;
+        }
+        let options = CopyOptions {
+            threads: 4,
+            ..CopyOptions::default()
+        };
+        for (idx, mode) in [None, Some(LinkMode::Copy), Some(LinkMode::Hardlink)]
+            .iter()
+            .enumerate()
+        {
+            let dst_path = dst_dir.path().join(idx.to_string());
+            match mode {
+                None => copy_with_options(&src_path, &dst_path, &options)?,
+                Some(mode) => link(&src_path, &dst_path, *mode, &options)?,
+            }
+            for i in 0..50 {
+                let rel = format!("dir{}/file{}", i % 7, i);
+                diff(src_path.join(&rel), dst_path.join(&rel))?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Report the error of a failing file with its context when copying on multiple threads.
+    fn for_each_parallel_error() {
+        let items: Vec<usize> = (0..100).collect();
+        let result = for_each_parallel(&items, 0, |i| match i {
+            42 => Error::result(format!("Could not process item {}!", i)),
+            _ => Ok(()),
+        });
+        assert_eq!(result.unwrap_err().msg, "Could not process item 42!");
+    }
+
+    #[test]
+    /// Copy a FIFO with each policy for special files.
+    fn copy_special_file() -> Result<()> {
+        let (src_dir, dst_dir) = setup()?;
+        let src_path = src_dir.path().join("some");
+        create_dir(&src_path)?;
+        let fifo = std::ffi::CString::new(src_path.join("fifo").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+        for (idx, special_files) in [
+            SpecialFiles::Skip,
+            SpecialFiles::Recreate,
+            SpecialFiles::Fail,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let dst_path = dst_dir.path().join(idx.to_string());
+            let options = CopyOptions {
+                special_files: *special_files,
+                ..CopyOptions::default()
+            };
+            let result = copy_with_options(&src_path, &dst_path, &options);
+            let dst_fifo = dst_path.join("fifo");
+            match special_files {
+                SpecialFiles::Skip => assert!(result.is_ok() && !dst_fifo.exists()),
+                SpecialFiles::Recreate => {
+                    use std::os::unix::fs::FileTypeExt;
+                    assert!(result.is_ok());
+                    assert!(file_type(&dst_fifo)?.is_fifo());
+                }
+                SpecialFiles::Fail => assert!(result.is_err()),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Copy a sparse file, preserving its holes.
+    fn copy_sparse() -> Result<()> {
+        use std::io::{Seek, SeekFrom, Write};
+        use std::os::unix::fs::MetadataExt;
+        let (_src_dir, _dst_dir, src_path, dst_path) = setup_single_file(Path::new("image"))?;
+        {
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&src_path)
+                .unwrap();
+            file.set_len(64 << 20).unwrap();
+            file.seek(SeekFrom::Start(32 << 20)).unwrap();
+            file.write_all(b"data in the middle").unwrap();
+        }
+        if src_path.metadata().unwrap().blocks() * 512 >= 64 << 20 {
+            // The file system does not support sparse files.
+            return Ok(());
+        }
+        copy(&src_path, &dst_path)?;
+        diff(&src_path, &dst_path)?;
+        let metadata = dst_path.metadata().unwrap();
+        assert_eq!(metadata.len(), 64 << 20);
+        assert!(metadata.blocks() * 512 < 1 << 20);
+        Ok(())
+    }
+
+    #[test]
+    /// Copy absolute and relative symlinks with each symlink policy.
+    fn copy_symlink_policies() -> Result<()> {
+        let (src_dir, dst_dir) = setup()?;
+        let src_path = src_dir.path().join("some");
+        create_dir(src_path.join("lib"))?;
+        create_file(src_path.join("lib/file"))?;
+        create_symlink(src_path.join("lib/file"), src_path.join("absolute"))?;
+        create_symlink(Path::new("lib/file"), &src_path.join("relative"))?;
+        for (idx, symlinks) in [
+            Symlinks::Preserve,
+            Symlinks::RejectAbsolute,
+            Symlinks::Relative,
+            Symlinks::Dereference,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let dst_path = dst_dir.path().join(idx.to_string());
+            let options = CopyOptions {
+                symlinks: *symlinks,
+                ..CopyOptions::default()
+            };
+            let result = copy_with_options(&src_path, &dst_path, &options);
+            let absolute = dst_path.join("absolute");
+            match symlinks {
+                Symlinks::Preserve => {
+                    assert!(result.is_ok());
+                    assert_eq!(absolute.read_link().unwrap(), src_path.join("lib/file"));
+                }
+                Symlinks::RejectAbsolute => assert!(result.is_err()),
+                Symlinks::Relative => {
+                    assert!(result.is_ok());
+                    assert_eq!(absolute.read_link().unwrap(), Path::new("lib/file"));
+                }
+                Symlinks::Dereference => {
+                    assert!(result.is_ok());
+                    assert!(file_type(&absolute)?.is_file());
+                    assert!(file_type(dst_path.join("relative"))?.is_file());
+                }
+            }
+            if *symlinks != Symlinks::Dereference && result.is_ok() {
+                assert_eq!(
+                    dst_path.join("relative").read_link().unwrap(),
+                    Path::new("lib/file")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Refuse to link symlinks that point outside of a directory.
+    fn link_confined_symlinks() -> Result<()> {
+        let (src_dir, dst_dir) = setup()?;
+        let src_path = src_dir.path().join("some");
+        create_dir(&src_path)?;
+        create_symlink(Path::new("../inside"), &src_path.join("link"))?;
+        let options = CopyOptions {
+            confine_symlinks: Some(dst_dir.path().to_path_buf()),
+            ..CopyOptions::default()
+        };
+        let dst_path = dst_dir.path().join("some");
+        link(&src_path, &dst_path, LinkMode::Copy, &options)?;
+        std::fs::remove_file(src_path.join("link")).unwrap();
+        create_symlink(Path::new("../../outside"), &src_path.join("link"))?;
+        assert!(link(&src_path, &dst_path, LinkMode::Copy, &options).is_err());
+        std::fs::remove_file(src_path.join("link")).unwrap();
+        create_symlink(Path::new("/etc/passwd"), &src_path.join("link"))?;
+        assert!(link(&src_path, &dst_path, LinkMode::Copy, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    /// Refuse to link symlinks that point outside of a directory through another symlink.
+    fn link_confined_symlink_chains() -> Result<()> {
+        let (src_dir, dst_dir) = setup()?;
+        let src_path = src_dir.path().join("some");
+        let dst_path = dst_dir.path().join("some");
+        create_dir(&src_path)?;
+        create_dir(dst_dir.path().join("sub"))?;
+        create_file(dst_dir.path().join("sub/file"))?;
+        let options = CopyOptions {
+            confine_symlinks: Some(dst_dir.path().to_path_buf()),
+            ..CopyOptions::default()
+        };
+        // `a/file` is inside, but `a/../..` is the parent of `dst_dir`, not `dst_dir`.
+        create_symlink(&dst_dir.path().join("sub"), &src_path.join("a"))?;
+        create_symlink(Path::new("a/file"), &src_path.join("b"))?;
+        link(&src_path, &dst_path, LinkMode::Copy, &options)?;
+        assert!(dst_path.join("b").is_file());
+        create_symlink(Path::new("a/../.."), &src_path.join("c"))?;
+        let (src, dst) = (src_path.join("c"), dst_path.join("c"));
+        assert!(link(&src, &dst, LinkMode::Copy, &options).is_err());
+        assert!(file_type(dst_path.join("c")).is_err());
+        // `..` after a component that does not exist yet is refused, since the component could
+        // still become a symlink.
+        create_symlink(Path::new("d/../../sub"), &src_path.join("e"))?;
+        let (src, dst) = (src_path.join("e"), dst_path.join("e"));
+        assert!(link(&src, &dst, LinkMode::Copy, &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    /// Refuse to rewrite absolute symlinks outside of the relative root.
+    fn relative_symlinks_outside_root() -> Result<()> {
+        let (src_dir, dst_dir) = setup()?;
+        let src_path = src_dir.path().join("some");
+        create_dir(&src_path)?;
+        create_file(src_dir.path().join("file"))?;
+        create_symlink(&src_dir.path().join("file"), &src_path.join("link"))?;
+        let options = CopyOptions {
+            symlinks: Symlinks::Relative,
+            relative_root: Some(src_dir.path().to_path_buf()),
+            ..CopyOptions::default()
+        };
+        copy_with_options(&src_path, dst_dir.path().join("inside"), &options)?;
+        assert_eq!(
+            dst_dir.path().join("inside/link").read_link().unwrap(),
+            Path::new("../file")
+        );
+        let options = CopyOptions {
+            relative_root: Some(src_path.clone()),
+            ..options
+        };
+        assert!(copy_with_options(&src_path, dst_dir.path().join("outside"), &options).is_err());
+        Ok(())
+    }
 }
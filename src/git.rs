@@ -5,34 +5,374 @@
 //! Git API
 
 use crate::error::{Error, Result};
-use crate::util::trim_newline;
-use derivative::Derivative;
-use log::{trace, warn};
-use std::cell::RefCell;
+use crate::memo::{Memo, Query};
+use crate::util::{lock, resolve_path, trim_newline};
+use log::{debug, info, trace, warn};
 use std::cmp::Ordering;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
 
-/// A Git object identifier.
-pub type Oid = String; // TODO: better Oid?
+/// The hash function with which a repository computes the identifiers of its objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ObjectFormat {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl ObjectFormat {
+    /// Number of hexadecimal digits of object identifiers in this format.
+    pub fn hex_len(self) -> usize {
+        match self {
+            ObjectFormat::Sha1 => 40,
+            ObjectFormat::Sha256 => 64,
+        }
+    }
+}
+
+impl Display for ObjectFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ObjectFormat::Sha1 => write!(f, "sha1"),
+            ObjectFormat::Sha256 => write!(f, "sha256"),
+        }
+    }
+}
+
+/// A Git object identifier, i.e., the hash of an object in lowercase hexadecimal digits: 40 digits
+/// in repositories with the SHA-1 object format and 64 digits in repositories with the SHA-256
+/// object format.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Oid(String);
+
+impl Oid {
+    /// Parse an object identifier in either object format.
+    pub fn parse(s: &str) -> Result<Oid> {
+        let hex = s
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        let len = [ObjectFormat::Sha1, ObjectFormat::Sha256]
+            .iter()
+            .any(|format| format.hex_len() == s.len());
+        match hex && len {
+            true => Ok(Oid(s.to_string())),
+            false => Error::result(format!("Invalid Git object ID {:?}!", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 
-/// A Git repository.
-#[derive(Derivative)]
-#[derivative(PartialEq, Hash, Eq, Debug)]
+    /// The object format in which this identifier was computed.
+    pub fn format(&self) -> ObjectFormat {
+        match self.0.len() {
+            40 => ObjectFormat::Sha1,
+            _ => ObjectFormat::Sha256,
+        }
+    }
+}
+
+impl std::str::FromStr for Oid {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Oid> {
+        Oid::parse(s)
+    }
+}
+
+impl Display for Oid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Debug for Oid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+/// A Git repository.  Clones of a `Repo` are handles to the same repository, which share the
+/// answers to queries and can be used from multiple threads.  Handles are equal if they refer to
+/// the same path.
+#[derive(Clone)]
 pub struct Repo {
-    pub path: PathBuf,
-    #[derivative(PartialEq = "ignore", Hash = "ignore", Debug = "ignore")]
-    ancestry_cache: RefCell<HashMap<(Oid, Oid), bool>>,
+    shared: Arc<Shared>,
+}
+
+impl PartialEq for Repo {
+    fn eq(&self, other: &Repo) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared) || self.path() == other.path()
+    }
+}
+
+impl Eq for Repo {}
+
+impl Hash for Repo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path().hash(state);
+    }
+}
+
+impl fmt::Debug for Repo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Repo")
+            .field("path", &self.shared.path)
+            .field("object_format", &self.shared.object_format)
+            .finish()
+    }
+}
+
+/// The state shared by all handles to a repository.
+struct Shared {
+    path: PathBuf,
+    object_format: ObjectFormat,
     submodule_paths: Vec<PathBuf>,
+    /// Answers to ancestry and path identity queries, optionally persisted across runs.
+    memo: Mutex<Memo>,
+    native: OnceLock<Option<native::Repository>>,
+    /// The Git directory shared by all worktrees of the repository.
+    git_dir: OnceLock<Option<PathBuf>>,
+    /// The commits at which the history of a shallow clone is truncated, read on first use and
+    /// forgotten when the clone is deepened.
+    shallow_boundary: Mutex<Option<Vec<Oid>>>,
+    /// Number of commits by which the next call of `deepen` deepens a shallow clone.
+    deepen_by: AtomicUsize,
 }
 
-/// A Git object.
-#[derive(PartialEq, Hash, Eq, Debug, Clone)]
-pub struct Object<'a> {
+/// A Git object.  Objects own a handle to their repository, so they can outlive the `Repo` they
+/// were created from and be sent to other threads.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Object {
     pub oid: Oid,
-    pub repo: &'a Repo,
+    pub repo: Repo,
+}
+
+impl Hash for Object {
+    /// Objects are hashed by their ID only; objects with the same ID in different repositories
+    /// are rare enough that comparing their repositories on collision is cheaper than hashing the
+    /// paths of the repositories.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.oid.hash(state);
+    }
+}
+
+/// In-process access to Git repositories through libgit2, which answers the most frequent queries
+/// without spawning a `git` process.  Each query returns `None` if it cannot be answered natively,
+/// in which case the caller falls back to running `git`.
+#[cfg(feature = "git2")]
+mod native {
+    use super::Oid;
+    use crate::util::lock;
+    use git2::{ErrorCode, ObjectType};
+    use log::debug;
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    /// A pool of libgit2 handles to a repository.  A `git2::Repository` must not be used by two
+    /// threads at once, so each query takes an idle handle (or opens a new one) and returns it
+    /// afterwards, which lets queries from different threads run concurrently.
+    pub struct Repository {
+        path: PathBuf,
+        /// The idle handles and the number of times the repository has been reopened.
+        idle: Mutex<(Vec<git2::Repository>, usize)>,
+    }
+
+    fn to_git2(oid: &Oid) -> Option<git2::Oid> {
+        git2::Oid::from_str(oid.as_str()).ok()
+    }
+
+    fn from_git2(oid: git2::Oid) -> Oid {
+        Oid(oid.to_string())
+    }
+
+    /// Convert the result of a libgit2 query, treating objects that do not exist like `git` does
+    /// (i.e., as `not_found`) and other errors as unanswerable.
+    fn answer<T>(result: Result<T, git2::Error>, not_found: T) -> Option<T> {
+        match result {
+            Ok(t) => Some(t),
+            Err(e) if e.code() == ErrorCode::NotFound => Some(not_found),
+            Err(e) => {
+                debug!("libgit2 failed, falling back to `git`: {}", e);
+                None
+            }
+        }
+    }
+
+    impl Repository {
+        pub fn open(path: &Path) -> Option<Repository> {
+            match git2::Repository::open(path) {
+                Ok(repo) => Some(Repository {
+                    path: path.to_path_buf(),
+                    idle: Mutex::new((vec![repo], 0)),
+                }),
+                Err(e) => {
+                    debug!("Could not open {:?} with libgit2: {}", path, e);
+                    None
+                }
+            }
+        }
+
+        /// Reopen the repository, so commits that were parsed before the history of a shallow
+        /// clone was deepened are parsed again with their parents.  Handles in use by other
+        /// threads are dropped when they are returned.
+        pub fn reopen(&self) {
+            let mut idle = lock(&self.idle);
+            idle.0.clear();
+            idle.1 += 1;
+        }
+
+        /// Run `query` on a handle to the repository that no other thread is using.
+        fn with<T>(&self, query: impl FnOnce(&git2::Repository) -> Option<T>) -> Option<T> {
+            let (repo, generation) = {
+                let mut idle = lock(&self.idle);
+                (idle.0.pop(), idle.1)
+            };
+            let repo = match repo {
+                Some(repo) => repo,
+                None => match git2::Repository::open(&self.path) {
+                    Ok(repo) => repo,
+                    Err(e) => {
+                        debug!("Could not open {:?} with libgit2: {}", self.path, e);
+                        return None;
+                    }
+                },
+            };
+            let answer = query(&repo);
+            let mut idle = lock(&self.idle);
+            if idle.1 == generation {
+                idle.0.push(repo);
+            }
+            answer
+        }
+
+        /// Returns whether commit `ancestor` is a proper ancestor of commit `descendant`.
+        pub fn is_ancestor(&self, ancestor: &Oid, descendant: &Oid) -> Option<bool> {
+            let ancestor = to_git2(ancestor)?;
+            let descendant = to_git2(descendant)?;
+            self.with(|repo| answer(repo.graph_descendant_of(descendant, ancestor), false))
+        }
+
+        /// Returns whether commit `oid` exists.
+        pub fn has_commit(&self, oid: &Oid) -> Option<bool> {
+            let oid = to_git2(oid)?;
+            self.with(|repo| answer(repo.find_commit(oid).map(|_| true), false))
+        }
+
+        /// Returns the number of commits reachable from either `a` or `b` but not from both.
+        pub fn distance(&self, a: &Oid, b: &Oid) -> Option<Option<usize>> {
+            let a = to_git2(a)?;
+            let b = to_git2(b)?;
+            self.with(|repo| {
+                answer(
+                    repo.graph_ahead_behind(a, b)
+                        .map(|(ahead, behind)| Some(ahead + behind)),
+                    None,
+                )
+            })
+        }
+
+        /// Returns whether `path` (relative to the root of the repository) is identical in the
+        /// trees of commits `a` and `b`.
+        pub fn path_is_same(&self, a: &Oid, b: &Oid, path: &Path) -> Option<bool> {
+            self.with(|repo| {
+                let entry = |oid: &Oid| -> Result<Option<(git2::Oid, i32)>, git2::Error> {
+                    let tree = repo
+                        .find_commit(git2::Oid::from_str(oid.as_str())?)?
+                        .tree()?;
+                    if path.as_os_str().is_empty() {
+                        return Ok(Some((tree.id(), 0)));
+                    }
+                    match tree.get_path(path) {
+                        Ok(entry) => Ok(Some((entry.id(), entry.filemode()))),
+                        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+                        Err(e) => Err(e),
+                    }
+                };
+                answer(entry(a).and_then(|a| entry(b).map(|b| a == b)), false)
+            })
+        }
+
+        /// Returns the ID of the tree or blob at `path` (relative to the root of the repository)
+        /// in `HEAD`, or `Some(None)` if `path` does not exist in `HEAD`.
+        pub fn head_path_oid(&self, path: &Path) -> Option<Option<Oid>> {
+            self.with(|repo| {
+                let oid = || -> Result<git2::Oid, git2::Error> {
+                    let tree = repo.head()?.peel_to_tree()?;
+                    match path.as_os_str().is_empty() {
+                        true => Ok(tree.id()),
+                        false => tree.get_path(path).map(|entry| entry.id()),
+                    }
+                };
+                answer(oid().map(|oid| Some(from_git2(oid))), None)
+            })
+        }
+    }
+
+    /// Returns the SHA-1 object identifier of a blob with the content of the file at `path`.
+    pub fn hash_file(path: &Path) -> Option<Oid> {
+        git2::Oid::hash_file(ObjectType::Blob, path)
+            .ok()
+            .map(from_git2)
+    }
+
+    /// Returns the SHA-1 object identifier of a blob with `content`.
+    pub fn hash_blob(content: &str) -> Option<Oid> {
+        git2::Oid::hash_object(ObjectType::Blob, content.as_bytes())
+            .ok()
+            .map(from_git2)
+    }
+}
+
+/// Stand-in for in-process access to Git repositories if Memora is built without libgit2, which
+/// answers no query.
+#[cfg(not(feature = "git2"))]
+mod native {
+    use super::Oid;
+    use std::path::Path;
+
+    pub struct Repository;
+
+    impl Repository {
+        pub fn open(_path: &Path) -> Option<Repository> {
+            None
+        }
+
+        pub fn is_ancestor(&self, _ancestor: &Oid, _descendant: &Oid) -> Option<bool> {
+            None
+        }
+
+        pub fn path_is_same(&self, _a: &Oid, _b: &Oid, _path: &Path) -> Option<bool> {
+            None
+        }
+
+        pub fn has_commit(&self, _oid: &Oid) -> Option<bool> {
+            None
+        }
+
+        pub fn reopen(&self) {}
+
+        pub fn distance(&self, _a: &Oid, _b: &Oid) -> Option<Option<usize>> {
+            None
+        }
+
+        pub fn head_path_oid(&self, _path: &Path) -> Option<Option<Oid>> {
+            None
+        }
+    }
+
+    pub fn hash_file(_path: &Path) -> Option<Oid> {
+        None
+    }
+
+    pub fn hash_blob(_content: &str) -> Option<Oid> {
+        None
+    }
 }
 
 fn path_str(path: &Path) -> &str {
@@ -44,17 +384,28 @@ impl Repo {
     /// Creates a Repo object for a path.
     pub fn new(path: PathBuf) -> Repo {
         let mut repo = Repo {
-            path,
-            ancestry_cache: RefCell::new(HashMap::new()),
-            submodule_paths: vec![],
+            shared: Arc::new(Shared {
+                path,
+                object_format: ObjectFormat::Sha1,
+                submodule_paths: vec![],
+                memo: Mutex::new(Memo::new()),
+                native: OnceLock::new(),
+                git_dir: OnceLock::new(),
+                shallow_boundary: Mutex::new(None),
+                deepen_by: AtomicUsize::new(64),
+            }),
+        };
+        let object_format = match repo.cmd_output(&["config", "--get", "extensions.objectFormat"]) {
+            Some(format) if format.eq_ignore_ascii_case("sha256") => ObjectFormat::Sha256,
+            _ => ObjectFormat::Sha1,
         };
         // Read submodule paths from `.gitmodules`.
-        repo.submodule_paths = {
+        let submodule_paths = {
             let submodule_path_strs = repo
                 .cmd_output(&[
                     "config",
                     "--file",
-                    &path_str(&repo.path.join(".gitmodules")),
+                    &path_str(&repo.path().join(".gitmodules")),
                     "--get-regexp",
                     r"submodule\..*\.path",
                 ])
@@ -71,18 +422,150 @@ impl Repo {
                 .map(|s| {
                     let p = PathBuf::from(s);
                     match p.as_path().is_relative() {
-                        true => repo.path.join(p),
+                        true => repo.path().join(p),
                         false => p,
                     }
                 })
                 .collect()
         };
+        let shared = Arc::get_mut(&mut repo.shared).expect("new repository is not shared");
+        shared.object_format = object_format;
+        shared.submodule_paths = submodule_paths;
         repo
     }
 
+    /// The path of the repository.
+    pub fn path(&self) -> &Path {
+        &self.shared.path
+    }
+
+    /// The object format of the repository.
+    pub fn object_format(&self) -> ObjectFormat {
+        self.shared.object_format
+    }
+
+    /// The Git directory shared by all worktrees of the repository.
+    fn git_dir(&self) -> Option<&Path> {
+        self.shared
+            .git_dir
+            .get_or_init(|| {
+                self.cmd_output(&["rev-parse", "--git-common-dir"])
+                    .map(|dir| self.path().join(dir))
+            })
+            .as_deref()
+    }
+
+    /// Persist the answers to ancestry and path identity queries in the Git directory of the
+    /// repository (shared by all worktrees), keeping at most `max_entries` answers.  If
+    /// `max_entries` is zero, answers are kept only in memory.
+    pub fn persist_memo(&self, max_entries: usize) {
+        if max_entries == 0 {
+            return;
+        }
+        match self.git_dir() {
+            Some(dir) => {
+                let path = dir.join("memora").join("memo");
+                *self.memo() = Memo::open(path, max_entries);
+            }
+            None => warn!("Could not find Git directory; not persisting Git queries."),
+        }
+    }
+
+    fn memo(&self) -> MutexGuard<'_, Memo> {
+        lock(&self.shared.memo)
+    }
+
+    /// The commits at which the history of a shallow clone is truncated.  Empty unless the
+    /// repository is a shallow clone.
+    pub fn shallow_boundary(&self) -> Vec<Oid> {
+        self.with_shallow_boundary(|boundary| boundary.to_vec())
+    }
+
+    /// Whether the repository is a shallow clone, in which the history of commits is truncated.
+    pub fn is_shallow(&self) -> bool {
+        self.with_shallow_boundary(|boundary| !boundary.is_empty())
+    }
+
+    fn with_shallow_boundary<T>(&self, f: impl FnOnce(&[Oid]) -> T) -> T {
+        let mut boundary = lock(&self.shared.shallow_boundary);
+        let boundary = boundary.get_or_insert_with(|| {
+            self.git_dir()
+                .and_then(|dir| std::fs::read_to_string(dir.join("shallow")).ok())
+                .map_or(vec![], |s| {
+                    s.lines().filter_map(|l| l.parse().ok()).collect()
+                })
+        });
+        f(boundary)
+    }
+
+    /// Whether the repository is a partial clone, from which objects are fetched on demand.
+    pub fn is_partial_clone(&self) -> bool {
+        self.cmd_output(&["config", "--get", "extensions.partialClone"])
+            .is_some()
+    }
+
+    /// Fetch more history into a shallow clone, twice as much as in the previous call.  Returns
+    /// whether the fetch succeeded.
+    pub fn deepen(&self) -> bool {
+        let by = self
+            .shared
+            .deepen_by
+            .fetch_update(AtomicOrdering::SeqCst, AtomicOrdering::SeqCst, |by| {
+                Some(by * 2)
+            })
+            .unwrap_or_default();
+        info!("Deepening shallow clone by {} commits.", by);
+        let deepened = self
+            .cmd_output(&["fetch", "--quiet", &format!("--deepen={}", by)])
+            .is_some();
+        if deepened {
+            // Commits may have become ancestors of each other through the fetched history.
+            self.memo().forget_transient();
+            *lock(&self.shared.shallow_boundary) = None;
+            if let Some(native) = self.native() {
+                native.reopen();
+            }
+        } else {
+            warn!("Could not deepen shallow clone!");
+        }
+        deepened
+    }
+
+    /// Whether the commit `oid` exists in the repository.
+    fn has_commit(&self, oid: &Oid) -> bool {
+        if let Some(exists) = self.native().and_then(|native| native.has_commit(oid)) {
+            return exists;
+        }
+        self.cmd_output(&["cat-file", "-e", &format!("{}^{{commit}}", oid)])
+            .is_some()
+    }
+
+    /// In-process access to the repository, opened on first use.
+    fn native(&self) -> Option<&native::Repository> {
+        self.shared
+            .native
+            .get_or_init(|| {
+                // libgit2 does not fetch missing objects on demand, so it would answer wrongly.
+                if self.is_partial_clone() {
+                    debug!("Not using libgit2 in partial clone {:?}.", self.path());
+                    return None;
+                }
+                if self.shared.object_format != ObjectFormat::Sha1 {
+                    debug!(
+                        "Not using libgit2 in repository {:?} with object format {}.",
+                        self.path(),
+                        self.shared.object_format
+                    );
+                    return None;
+                }
+                native::Repository::open(self.path())
+            })
+            .as_ref()
+    }
+
     fn custom_cmd(&self, cmd: &str, args: &[&str]) -> Command {
         let mut tmp = Command::new(cmd);
-        tmp.current_dir(&self.path);
+        tmp.current_dir(self.path());
         for a in args {
             tmp.arg(a);
         }
@@ -122,37 +605,144 @@ impl Repo {
     /// Returns the last commit modifying `path`.  Returns `None` if there is no such commit.
     pub fn last_commit_on_path(&self, path: &Path) -> Option<Object> {
         self.cmd_output(&["log", "-n", "1", "--pretty=format:%H", "--", path_str(path)])
-            .and_then(|s| {
-                if s.is_empty() {
-                    None
-                } else {
-                    Some(Object::new(s, self))
-                }
-            })
+            .and_then(|s| s.parse().ok())
+            .map(|oid| Object::new(oid, self))
     }
 
-    /// Returns true if a path contains uncommitted changes.  Returns false if the path has no
-    /// uncommitted changes or has not been added to the repository.
-    pub fn has_uncommitted_changes(&self, path: &Path) -> bool {
-        // `git ls-files` does not work with paths inside submodules.  Thus, if a path is
-        // inside a submodule, do `git ls-files` on the submodule path instead.
+    /// Returns the object identifier Git assigns to a blob with the given content, without writing
+    /// the blob to the repository.  Returns `None` if `git hash-object` fails.
+    pub fn hash_object(&self, content: &str) -> Option<Oid> {
+        // libgit2 hashes only in the SHA-1 object format.
+        if self.shared.object_format == ObjectFormat::Sha1 {
+            if let Some(oid) = native::hash_blob(content) {
+                return Some(oid);
+            }
+        }
+        use std::io::Write;
+        use std::process::Stdio;
+        let mut child = self
+            .cmd(&["hash-object", "--stdin"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        child.stdin.take()?.write_all(content.as_bytes()).ok()?;
+        let output = child.wait_with_output().ok()?;
+        trace!("{:?}", output);
+        if output.status.success() {
+            String::from_utf8(output.stdout)
+                .ok()
+                .and_then(|s| trim_newline(s).parse().ok())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the object identifier Git assigns to a blob with the content of the file at `path`
+    /// (without applying any filters).  Returns `None` if the file cannot be read.
+    pub fn hash_file(&self, path: &Path) -> Option<Oid> {
+        if self.shared.object_format == ObjectFormat::Sha1 {
+            if let Some(oid) = native::hash_file(path) {
+                return Some(oid);
+            }
+        }
+        self.cmd_output(&["hash-object", "--no-filters", "--", path_str(path)])
+            .and_then(|s| s.parse().ok())
+    }
+
+    /// Returns the absolute path of `path`, or the path of the submodule containing `path` if
+    /// `path` is inside a submodule.
+    ///
+    /// `git ls-files` does not work with paths inside submodules.  Thus, if a path is inside a
+    /// submodule, `git ls-files` has to be run on the submodule path instead.
+    fn superproject_path(&self, path: &Path) -> PathBuf {
         // First, make the path absolute.
         let path = match path.is_relative() {
-            true => self.path.join(path),
+            true => self.path().join(path),
             false => path.to_owned(),
         };
         // Second, if the path is inside a submodule, replace it with the path of the submodule.
-        let path = {
-            let mut path = path;
-            for s in &self.submodule_paths {
-                if path.starts_with(s) {
-                    path = s.to_owned();
-                    break;
-                }
+        for s in &self.shared.submodule_paths {
+            if path.starts_with(s) {
+                return s.to_owned();
             }
-            path
+        }
+        path
+    }
+
+    /// Returns true if a path (or, for a directory, any path below it) is tracked by Git.  Paths
+    /// inside submodules are considered tracked if the submodule is tracked.
+    pub fn is_tracked(&self, path: &Path) -> bool {
+        let path = self.superproject_path(path);
+        self.cmd_output(&["ls-files", "--error-unmatch", "--", path_str(&path)])
+            .is_some()
+    }
+
+    /// Returns the paths (relative to the root of the repository) of the files tracked by Git in
+    /// directory `dir`, in the order of `git ls-files`.  Submodules are listed as one path.
+    pub fn tracked_files(&self, dir: &Path) -> Vec<PathBuf> {
+        let dir = match dir.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => dir,
         };
-        // Finally, run `git ls-files` on the path.
+        match self.cmd_output(&["ls-files", "-z", "--", path_str(dir)]) {
+            None => {
+                warn!(
+                    "git ls-files {:?} failed, assuming no files are tracked",
+                    dir
+                );
+                vec![]
+            }
+            Some(s) => s
+                .split('\0')
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from)
+                .collect(),
+        }
+    }
+
+    /// Returns the ID of the tree or blob at `path` in `HEAD`.  For a path inside a submodule, this
+    /// is the commit of the submodule.  Returns `None` if `path` does not exist in `HEAD`.
+    pub fn head_path_oid(&self, path: &Path) -> Option<Oid> {
+        let path = self.superproject_path(path);
+        let path = resolve_path(path.strip_prefix(self.path()).ok()?);
+        if let Some(oid) = self.native().and_then(|native| native.head_path_oid(&path)) {
+            return oid;
+        }
+        self.cmd_output(&[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("HEAD:{}", path_str(&path)),
+        ])
+        .and_then(|s| s.parse().ok())
+    }
+
+    /// Returns a key for the contents of `inputs` in `HEAD`: the ID of a blob that lists the tree
+    /// or blob ID of each input (see [`head_path_oid`](#method.head_path_oid)).  Inputs that are
+    /// identical in two commits have the same key, regardless of the history of the commits.
+    /// Returns `None` if an input does not exist in `HEAD`.
+    pub fn tree_key(&self, inputs: &[PathBuf]) -> Option<Oid> {
+        let mut lines: Vec<String> = inputs
+            .iter()
+            .map(|inp| {
+                let oid = self.head_path_oid(inp);
+                if oid.is_none() {
+                    warn!("{:?} does not exist in HEAD!", inp);
+                }
+                oid.map(|oid| format!("{} {}\n", oid, resolve_path(inp).to_string_lossy()))
+            })
+            .collect::<Option<_>>()?;
+        lines.sort();
+        lines.dedup();
+        self.hash_object(&format!("memora input trees 1\n{}", lines.concat()))
+    }
+
+    /// Returns true if a path contains uncommitted changes.  Returns false if the path has no
+    /// uncommitted changes or has not been added to the repository.
+    pub fn has_uncommitted_changes(&self, path: &Path) -> bool {
+        let path = self.superproject_path(path);
+        // Run `git ls-files` on the path.
         let ls_files = self
             .cmd(&["ls-files", "-z", "--", path_str(&path)])
             .stdout(std::process::Stdio::piped())
@@ -189,8 +779,8 @@ impl Repo {
     /// Returns the first of a set of objects according to a given ordering.  Returns an error if
     /// the set is empty or any two of the objects are incomparable.
     fn first_ordered_object<'a>(
-        &'a self,
-        objects: &'a HashSet<Object<'a>>,
+        &self,
+        objects: &'a HashSet<Object>,
         ord: Ordering,
     ) -> Result<&'a Object> {
         if objects.len() == 0 {
@@ -218,22 +808,22 @@ impl Repo {
 
     /// Returns the youngest (= furthest from root) of a set of objects.  Returns an error if the
     /// set is empty or any two of the objects are incomparable.
-    pub fn youngest_object<'a>(&'a self, objects: &'a HashSet<Object<'a>>) -> Result<&'a Object> {
+    pub fn youngest_object<'a>(&self, objects: &'a HashSet<Object>) -> Result<&'a Object> {
         self.first_ordered_object(objects, Ordering::Less)
     }
 
     /// Returns the oldest (= closest to root) of a set of objects.  Returns an error if the set is
     /// empty or any of two of the objects are incomparable.
-    pub fn oldest_object<'a>(&'a self, objects: &'a HashSet<Object<'a>>) -> Result<&'a Object> {
+    pub fn oldest_object<'a>(&self, objects: &'a HashSet<Object>) -> Result<&'a Object> {
         self.first_ordered_object(objects, Ordering::Greater)
     }
 
     /// Determine the oldest common descendant of a set of objects on the current branch.  Returns
     /// an error if any two of the objects do not have a common descendant.
-    pub fn oldest_common_descendant_on_current_branch<'a>(
-        &'a self,
-        objects: &'a HashSet<Object<'a>>,
-    ) -> Result<Object<'a>> {
+    pub fn oldest_common_descendant_on_current_branch(
+        &self,
+        objects: &HashSet<Object>,
+    ) -> Result<Object> {
         if objects.len() == 0 {
             return Error::result("no objects given");
         }
@@ -256,31 +846,45 @@ impl Repo {
     }
 
     fn object_is_ancestor_of(&self, ancestor: &Object, other: &Object) -> bool {
-        let key = (ancestor.oid.clone(), other.oid.clone());
-        if let Some(entry) = self.ancestry_cache.borrow().get(&key) {
-            return *entry;
+        let query = Query::Ancestry(ancestor.oid.clone(), other.oid.clone());
+        if let Some(answer) = self.memo().get(&query) {
+            return answer;
         }
-        let output = self.cmd_output(&[
-            "rev-list",
-            "--ancestry-path",
-            &format!("{}..{}", ancestor.oid, other.oid),
-        ]);
-        let entry = match output {
-            None => false,
-            Some(s) => s.len() > 0,
+        let native = self
+            .native()
+            .and_then(|native| native.is_ancestor(&ancestor.oid, &other.oid));
+        let answer = match native {
+            Some(answer) => answer,
+            None => {
+                let output = self.cmd_output(&[
+                    "rev-list",
+                    "--ancestry-path",
+                    &format!("{}..{}", ancestor.oid, other.oid),
+                ]);
+                match output {
+                    None => false,
+                    Some(s) => s.len() > 0,
+                }
+            }
         };
-        self.ancestry_cache.borrow_mut().insert(key, entry);
-        entry
+        // A commit stays an ancestor, but a commit that is not an ancestor may become one once
+        // missing commits or truncated history are fetched.
+        let persistent = answer
+            || (!self.is_shallow()
+                && self.has_commit(&ancestor.oid)
+                && self.has_commit(&other.oid));
+        self.memo().insert(query, answer, persistent);
+        answer
     }
 }
 
-impl<'a> Display for Object<'a> {
+impl Display for Object {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.oid)
     }
 }
 
-impl<'a> PartialOrd for Object<'a> {
+impl PartialOrd for Object {
     fn partial_cmp(&self, other: &Object) -> Option<Ordering> {
         if self == other {
             return Some(Ordering::Equal);
@@ -294,11 +898,11 @@ impl<'a> PartialOrd for Object<'a> {
     }
 }
 
-impl<'a> Object<'a> {
-    pub fn new(oid: Oid, repo: &'a Repo) -> Object<'a> {
+impl Object {
+    pub fn new(oid: Oid, repo: &Repo) -> Object {
         Object {
-            oid: oid,
-            repo: repo,
+            oid,
+            repo: repo.clone(),
         }
     }
 
@@ -317,19 +921,65 @@ impl<'a> Object<'a> {
         if self.repo != ancestor.repo {
             return false;
         }
-        // TODO: need to relativize path?
-        let output = self.repo.cmd_output(&[
-            "diff",
-            "--quiet",
-            &format!("{}..{}", ancestor.oid, self.oid),
-            "--",
-            path_str(path),
-        ]);
-        output.is_some()
+        let relative = resolve_path(path.strip_prefix(self.repo.path()).unwrap_or(path));
+        // The answer does not depend on the order of the commits.
+        let (a, b) = match ancestor.oid <= self.oid {
+            true => (&ancestor.oid, &self.oid),
+            false => (&self.oid, &ancestor.oid),
+        };
+        let query = Query::PathIdentity(a.clone(), b.clone(), relative.clone());
+        if let Some(answer) = self.repo.memo().get(&query) {
+            return answer;
+        }
+        let native = self
+            .repo
+            .native()
+            .filter(|_| relative.is_relative())
+            .and_then(|native| native.path_is_same(&ancestor.oid, &self.oid, &relative));
+        let answer = match native {
+            Some(same) => same,
+            None => self
+                .repo
+                .cmd_output(&[
+                    "diff",
+                    "--quiet",
+                    &format!("{}..{}", ancestor.oid, self.oid),
+                    "--",
+                    path_str(&self.repo.path().join(&relative)),
+                ])
+                .is_some(),
+        };
+        // The paths may differ only because one of the commits has not been fetched yet.
+        let persistent = answer || (self.repo.has_commit(a) && self.repo.has_commit(b));
+        self.repo.memo().insert(query, answer, persistent);
+        answer
+    }
+
+    /// Returns the distance between this commit and `obj` in the commit graph, i.e., the number of
+    /// commits that are reachable from either of them but not from both.  Returns `None` if the
+    /// distance cannot be determined (e.g., if one of the commits does not exist).
+    pub fn distance_to(&self, obj: &Object) -> Option<usize> {
+        if self.repo != obj.repo {
+            return None;
+        }
+        if let Some(distance) = self
+            .repo
+            .native()
+            .and_then(|native| native.distance(&self.oid, &obj.oid))
+        {
+            return distance;
+        }
+        self.repo
+            .cmd_output(&[
+                "rev-list",
+                "--count",
+                &format!("{}...{}", self.oid, obj.oid),
+            ])
+            .and_then(|s| s.parse().ok())
     }
 
     /// Get descendants of this commit on the current branch, in chronological order.
-    fn descendants_on_current_branch(&self) -> Vec<Object<'a>> {
+    fn descendants_on_current_branch(&self) -> Vec<Object> {
         match self.repo.cmd_output(&[
             "rev-list",
             "--ancestry-path",
@@ -338,7 +988,8 @@ impl<'a> Object<'a> {
         ]) {
             Some(s) => s
                 .lines()
-                .map(|line| Object::new(line.to_string(), self.repo))
+                .filter_map(|line| line.parse().ok())
+                .map(|oid| Object::new(oid, &self.repo))
                 .collect(),
             None => vec![],
         }
@@ -368,8 +1019,8 @@ mod tests {
         }
         fn past_commit(&self, n_commits_ago: usize) -> Option<Object> {
             self.cmd_output(&["rev-parse", &format!("HEAD~{}", n_commits_ago)])
-                .and_then(|oup| oup.lines().next().map(|l| l.to_string()))
-                .map(|head_commit| Object::new(head_commit.to_string(), &self))
+                .and_then(|oup| oup.lines().next().and_then(|l| l.parse().ok()))
+                .map(|head_commit| Object::new(head_commit, &self))
         }
     }
 
@@ -456,7 +1107,7 @@ mod tests {
 
     fn rand_commits_on_file(repo: &Repo, rel_path: &str, n_commits: usize) -> Result<()> {
         let mut rng = rand::thread_rng();
-        let mut file = create_file(repo.path.join(rel_path))?;
+        let mut file = create_file(repo.path().join(rel_path))?;
         for _i in 0..n_commits {
             write_file(&mut file, &rand_string(&mut rng, 10))?;
             repo.cmd_assert(&["add", rel_path]);
@@ -471,10 +1122,7 @@ mod tests {
         Ok((repo, tmp_dir))
     }
 
-    fn create_two_incomparable_commits<'a>(
-        repo: &'a Repo,
-        path: &str,
-    ) -> Result<(Object<'a>, Object<'a>)> {
+    fn create_two_incomparable_commits(repo: &Repo, path: &str) -> Result<(Object, Object)> {
         repo.cmd_assert(&["checkout", "-b", "some_branch"]);
         rand_commits_on_file(&repo, path, 1)?;
         let some_commit = repo.last_commit().unwrap();
@@ -666,7 +1314,7 @@ mod tests {
         let mut rws = RepoWithSubmodule::setup()?;
         // Assert that absolute path is detected.
         assert_eq!(
-            rws.outer_repo.submodule_paths,
+            rws.outer_repo.shared.submodule_paths,
             vec![rws.submodule_path.clone()]
         );
         // Modify to relative path and make sure that is converted to an absolute path.
@@ -679,7 +1327,7 @@ mod tests {
         ]);
         rws.outer_repo = Repo::new(rws.outer_dir.path().to_owned());
         assert_eq!(
-            rws.outer_repo.submodule_paths,
+            rws.outer_repo.shared.submodule_paths,
             vec![rws.submodule_path.clone()]
         );
         Ok(())
@@ -705,4 +1353,153 @@ mod tests {
         assert_eq!(rws.outer_repo.has_uncommitted_changes(&file_path), true);
         Ok(())
     }
+
+    #[test]
+    fn native_and_subprocess_agree() -> Result<()> {
+        let (repo, tmp_dir) = setup_with_commits_on_file("some_file", 2)?;
+        create_dir(&tmp_dir.path().join("dir"))?;
+        rand_commits_on_file(&repo, "dir/other_file", 1)?;
+        let (some_commit, another_commit) = create_two_incomparable_commits(&repo, "dir/file")?;
+        let subprocess = Repo::new(repo.path().to_path_buf());
+        subprocess.shared.native.set(None).ok();
+        if repo.native().is_none() {
+            // Built without libgit2.
+            return Ok(());
+        }
+        let commits: Vec<Oid> = (0..4)
+            .map(|n| repo.past_commit(n).unwrap().oid)
+            .chain(vec![some_commit.oid.clone(), another_commit.oid.clone()])
+            .chain(vec!["0".repeat(40).parse()?])
+            .collect();
+        for a in &commits {
+            for b in &commits {
+                let (a_native, b_native) =
+                    (Object::new(a.clone(), &repo), Object::new(b.clone(), &repo));
+                let (a_subproc, b_subproc) = (
+                    Object::new(a.clone(), &subprocess),
+                    Object::new(b.clone(), &subprocess),
+                );
+                assert_eq!(
+                    a_native.is_ancestor_of(&b_native),
+                    a_subproc.is_ancestor_of(&b_subproc),
+                    "{} ancestor of {}",
+                    a,
+                    b
+                );
+                for path in &["some_file", "dir", "dir/file", "missing", "."] {
+                    assert_eq!(
+                        b_native.path_is_same_as(&a_native, Path::new(path)),
+                        b_subproc.path_is_same_as(&a_subproc, Path::new(path)),
+                        "{} in {} and {}",
+                        path,
+                        a,
+                        b
+                    );
+                }
+            }
+        }
+        assert_eq!(
+            repo.hash_object("content"),
+            subprocess.hash_object("content")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn native_concurrent_queries() -> Result<()> {
+        let (repo, _tmp_dir) = setup_with_commits_on_file("some_file", 3)?;
+        let other = Repo::new(repo.path().to_path_buf());
+        assert_eq!(repo, other);
+        let native = match repo.native() {
+            Some(native) => native,
+            // Built without libgit2.
+            None => return Ok(()),
+        };
+        let (old, new) = (repo.past_commit(2).unwrap(), repo.past_commit(0).unwrap());
+        std::thread::scope(|scope| {
+            for n in 0..4 {
+                let (old, new) = (&old.oid, &new.oid);
+                scope.spawn(move || {
+                    for _ in 0..16 {
+                        if n == 0 {
+                            native.reopen();
+                        }
+                        assert_eq!(native.is_ancestor(old, new), Some(true));
+                        assert_eq!(native.is_ancestor(new, old), Some(false));
+                        assert_eq!(native.distance(old, new), Some(Some(2)));
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    #[test]
+    fn persistent_memo() -> Result<()> {
+        let (repo, tmp_dir) = setup_with_commits_on_file("some_file", 2)?;
+        repo.persist_memo(10);
+        let (old, new) = {
+            let (old, new) = (repo.past_commit(1).unwrap(), repo.past_commit(0).unwrap());
+            let missing = Object::new("0".repeat(40).parse()?, &repo);
+            assert!(old.is_ancestor_of(&new));
+            assert!(!new.is_ancestor_of(&old));
+            assert!(!missing.is_ancestor_of(&new));
+            assert!(!new.path_is_same_as(&old, Path::new("some_file")));
+            (old.oid, new.oid)
+        };
+        // The memo is saved when the last handle to the repository is dropped.
+        drop(repo);
+        let path = tmp_dir.path().join(".git").join("memora").join("memo");
+        let memo = std::fs::read_to_string(&path)
+            .map_err(|cause| Error::chain(format!("Could not read {:?}:", path), cause))?;
+        assert_eq!(memo.lines().count(), 4);
+        assert!(!memo.contains(&"0".repeat(40)));
+        // Answers are taken from the memo instead of the repository.
+        let memo = memo.replace(
+            &format!("a {} {} 1", old, new),
+            &format!("a {} {} 0", old, new),
+        );
+        std::fs::write(&path, memo)
+            .map_err(|cause| Error::chain(format!("Could not write {:?}:", path), cause))?;
+        let repo = Repo::new(tmp_dir.path().to_path_buf());
+        repo.persist_memo(10);
+        assert!(!Object::new(old, &repo).is_ancestor_of(&Object::new(new, &repo)));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_oids() {
+        let sha1 = "0123456789abcdef0123456789abcdef01234567";
+        let sha256 = format!("{}{}", sha1, "89abcdef0123456789abcdef");
+        assert_eq!(Oid::parse(sha1).unwrap().as_str(), sha1);
+        assert_eq!(sha256.parse::<Oid>().unwrap().to_string(), sha256);
+        assert!(Oid::parse(&sha1[1..]).is_err());
+        assert!(Oid::parse(&sha1.to_uppercase()).is_err());
+        assert!(Oid::parse(&format!("{}g", &sha1[1..])).is_err());
+        assert!(Oid::parse(&format!("{}0", sha256)).is_err());
+        assert!(Oid::parse("").is_err());
+    }
+
+    #[test]
+    fn sha256_repository() -> Result<()> {
+        let tmp = TempDir::new("memora-test-git")
+            .map_err(|cause| Error::chain("Could not create temporary directory:", cause))?;
+        let repo = Repo::new(tmp.path().to_path_buf());
+        repo.cmd_assert(&["init", "--object-format=sha256"]);
+        repo_config_user(&repo);
+        let repo = Repo::new(tmp.path().to_path_buf());
+        assert_eq!(repo.object_format(), ObjectFormat::Sha256);
+        rand_commits_on_file(&repo, "some_file", 2)?;
+        let (old, new) = (repo.past_commit(1).unwrap(), repo.past_commit(0).unwrap());
+        assert_eq!(new.oid.format(), ObjectFormat::Sha256);
+        assert!(old.is_ancestor_of(&new));
+        assert!(!new.path_is_same_as(&old, Path::new("some_file")));
+        let blob = repo.hash_object("content").unwrap();
+        assert_eq!(blob.format(), ObjectFormat::Sha256);
+        assert_eq!(
+            repo.hash_file(&tmp.path().join("some_file")),
+            repo.head_path_oid(&tmp.path().join("some_file"))
+        );
+        Ok(())
+    }
 }
@@ -13,6 +13,9 @@ pub mod config;
 pub mod error;
 pub mod fs;
 pub mod git;
+pub mod memo;
+pub mod pattern;
+pub mod settings;
 pub mod util;
 
 #[cfg(test)]
@@ -0,0 +1,275 @@
+// Copyright 2020 Andreas Kurth
+//
+// SPDX-License-Identifier: (Apache-2.0 OR MIT)
+
+//! Persistent memoization of Git queries
+//!
+//! Whether a commit is an ancestor of another commit and whether a path is identical in two commits
+//! never changes once both commits exist, so the answers to these queries can be kept across runs
+//! of Memora.  A `Memo` keeps the answers in memory and, if it was opened on a file, writes the
+//! most recently used answers back to that file when it is dropped.
+
+use crate::error::{Error, Result};
+use crate::git::Oid;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// First line of a memo file, which identifies its format.
+const HEADER: &str = "memora memo 1";
+
+/// A memoized Git query.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Query {
+    /// Whether the first commit is a proper ancestor of the second commit.
+    Ancestry(Oid, Oid),
+    /// Whether a path (relative to the root of the repository) is identical in two commits.
+    PathIdentity(Oid, Oid, PathBuf),
+}
+
+impl Query {
+    /// Format the query and its `answer` as a line of a memo file.  Returns `None` if the query
+    /// cannot be represented in a line.
+    fn to_line(&self, answer: bool) -> Option<String> {
+        let answer = answer as u8;
+        match self {
+            Query::Ancestry(a, b) => Some(format!("a {} {} {}", a, b, answer)),
+            Query::PathIdentity(a, b, path) => {
+                let path = path.to_str().filter(|p| !p.contains('\n'))?;
+                Some(format!("p {} {} {} {}", a, b, answer, path))
+            }
+        }
+    }
+
+    /// Parse a line of a memo file into a query and its answer.
+    fn from_line(line: &str) -> Option<(Query, bool)> {
+        let mut fields = line.splitn(5, ' ');
+        let kind = fields.next()?;
+        let a: Oid = fields.next()?.parse().ok()?;
+        let b: Oid = fields.next()?.parse().ok()?;
+        let answer = match fields.next()? {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+        let query = match (kind, fields.next()) {
+            ("a", None) => Query::Ancestry(a, b),
+            ("p", Some(path)) => Query::PathIdentity(a, b, PathBuf::from(path)),
+            _ => return None,
+        };
+        Some((query, answer))
+    }
+}
+
+/// The memoized answer to a query.
+#[derive(Debug, Clone, Copy)]
+struct Answer {
+    value: bool,
+    /// Whether the answer is kept across runs.
+    persistent: bool,
+    /// Logical time of the last use, for evicting the least recently used answers.
+    last_use: u64,
+}
+
+/// Answers to Git queries, optionally backed by a file.
+#[derive(Debug, Default)]
+pub struct Memo {
+    /// The file the answers are loaded from and saved to.
+    path: Option<PathBuf>,
+    /// Maximum number of answers saved to the file.
+    max_entries: usize,
+    answers: HashMap<Query, Answer>,
+    clock: u64,
+    modified: bool,
+}
+
+/// Read the answers in the memo file at `path`, in order from least to most recently used.
+fn read(path: &Path) -> Result<Vec<(Query, bool)>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Error::result(format!("Could not read memo {:?}: {}", path, e)),
+    };
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return Error::result(format!("Memo {:?} has an unknown format!", path));
+    }
+    Ok(lines.filter_map(Query::from_line).collect())
+}
+
+impl Memo {
+    /// Create a memo that is kept only in memory.
+    pub fn new() -> Memo {
+        Memo::default()
+    }
+
+    /// Open the memo file at `path`, which keeps at most `max_entries` answers.  If the file cannot
+    /// be read, the memo starts empty.
+    pub fn open(path: PathBuf, max_entries: usize) -> Memo {
+        let mut memo = Memo::new();
+        memo.max_entries = max_entries;
+        match read(&path) {
+            Ok(answers) => {
+                debug!("Loaded {} answers from memo {:?}.", answers.len(), path);
+                for (query, value) in answers {
+                    memo.insert(query, value, true);
+                }
+            }
+            Err(e) => warn!("Ignoring memo: {}", e),
+        }
+        memo.modified = false;
+        memo.path = Some(path);
+        memo
+    }
+
+    /// The memoized answer to `query`, if any.
+    pub fn get(&mut self, query: &Query) -> Option<bool> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.answers.get_mut(query).map(|answer| {
+            answer.last_use = clock;
+            answer.value
+        })
+    }
+
+    /// Memoize `value` as the answer to `query`.  Only `persistent` answers are saved to the file,
+    /// so answers that may change (e.g., because a commit has not been fetched yet) must not be
+    /// persistent.
+    pub fn insert(&mut self, query: Query, value: bool, persistent: bool) {
+        self.clock += 1;
+        self.modified |= persistent;
+        self.answers.insert(
+            query,
+            Answer {
+                value,
+                persistent,
+                last_use: self.clock,
+            },
+        );
+    }
+
+    /// Forget the answers that are not persistent, e.g., because missing commits have been
+    /// fetched.
+    pub fn forget_transient(&mut self) {
+        self.answers.retain(|_, answer| answer.persistent);
+    }
+
+    /// Save the persistent answers to the file, keeping the most recently used ones.  Answers that
+    /// other processes have saved to the file in the meantime are merged as least recently used.
+    pub fn save(&mut self) -> Result<()> {
+        let path = match (&self.path, self.modified) {
+            (Some(path), true) => path,
+            _ => return Ok(()),
+        };
+        let saved = read(path).unwrap_or_default();
+        let mut lines: Vec<(u64, String)> = saved
+            .iter()
+            .filter(|(query, _)| !self.answers.contains_key(query))
+            .filter_map(|(query, value)| query.to_line(*value).map(|line| (0, line)))
+            .collect();
+        lines.extend(
+            self.answers
+                .iter()
+                .filter(|(_, answer)| answer.persistent)
+                .filter_map(|(query, answer)| {
+                    query
+                        .to_line(answer.value)
+                        .map(|line| (answer.last_use, line))
+                }),
+        );
+        lines.sort_by_key(|(last_use, _)| *last_use);
+        let skip = lines.len().saturating_sub(self.max_entries);
+        let mut contents = format!("{}\n", HEADER);
+        for (_, line) in &lines[skip..] {
+            contents.push_str(line);
+            contents.push('\n');
+        }
+        crate::fs::create_parents(path)?;
+        let tmp = path.with_extension(crate::util::tmp_suffix());
+        std::fs::write(&tmp, contents)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|cause| Error::chain(format!("Could not write memo {:?}!", path), cause))?;
+        debug!("Saved {} answers to memo {:?}.", lines.len() - skip, path);
+        self.modified = false;
+        Ok(())
+    }
+}
+
+impl Drop for Memo {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    /// An object identifier that repeats the hexadecimal digit `digit`.
+    fn oid(digit: &str) -> Oid {
+        digit.repeat(40).parse().unwrap()
+    }
+
+    fn ancestry(a: &str, b: &str) -> Query {
+        Query::Ancestry(oid(a), oid(b))
+    }
+
+    #[test]
+    fn persist_answers() -> Result<()> {
+        let tmp = TempDir::new("memora-test-memo")
+            .map_err(|cause| Error::chain("Could not create temporary directory:", cause))?;
+        let path = tmp.path().join("memora").join("memo");
+        let identity = Query::PathIdentity(oid("a"), oid("b"), "x y".into());
+        {
+            let mut memo = Memo::open(path.clone(), 10);
+            memo.insert(ancestry("a", "b"), true, true);
+            memo.insert(ancestry("b", "a"), false, false);
+            memo.insert(identity.clone(), false, true);
+        }
+        let mut memo = Memo::open(path.clone(), 10);
+        assert_eq!(memo.get(&ancestry("a", "b")), Some(true));
+        assert_eq!(memo.get(&ancestry("b", "a")), None);
+        assert_eq!(memo.get(&identity), Some(false));
+        Ok(())
+    }
+
+    #[test]
+    fn evict_least_recently_used() -> Result<()> {
+        let tmp = TempDir::new("memora-test-memo")
+            .map_err(|cause| Error::chain("Could not create temporary directory:", cause))?;
+        let path = tmp.path().join("memo");
+        {
+            let mut memo = Memo::open(path.clone(), 2);
+            memo.insert(ancestry("a", "b"), true, true);
+            memo.insert(ancestry("b", "c"), true, true);
+            memo.insert(ancestry("c", "d"), true, true);
+            memo.get(&ancestry("a", "b"));
+        }
+        let mut memo = Memo::open(path.clone(), 2);
+        assert_eq!(memo.get(&ancestry("a", "b")), Some(true));
+        assert_eq!(memo.get(&ancestry("b", "c")), None);
+        assert_eq!(memo.get(&ancestry("c", "d")), Some(true));
+        Ok(())
+    }
+
+    #[test]
+    fn merge_concurrent_answers() -> Result<()> {
+        let tmp = TempDir::new("memora-test-memo")
+            .map_err(|cause| Error::chain("Could not create temporary directory:", cause))?;
+        let path = tmp.path().join("memo");
+        let mut first = Memo::open(path.clone(), 10);
+        let mut second = Memo::open(path.clone(), 10);
+        first.insert(ancestry("a", "b"), true, true);
+        second.insert(ancestry("b", "c"), false, true);
+        first.save()?;
+        second.save()?;
+        let mut memo = Memo::open(path.clone(), 10);
+        assert_eq!(memo.get(&ancestry("a", "b")), Some(true));
+        assert_eq!(memo.get(&ancestry("b", "c")), Some(false));
+        Ok(())
+    }
+}
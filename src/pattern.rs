@@ -0,0 +1,281 @@
+// Copyright 2020 Andreas Kurth
+//
+// SPDX-License-Identifier: (Apache-2.0 OR MIT)
+
+//! Wildcards in the names and paths of Pattern Artifacts
+
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+/// Regex for the values of wildcards.  Wildcards match one or multiple characters among
+/// alphanumerics, underscore, dash, dot, and plus non-greedily.
+const VALUE_REGEX: &str = r"[[[:alnum:]]_\-.+]+?";
+
+/// Values of wildcards, as pairs of wildcard name and value.
+pub type Bindings = Vec<(String, String)>;
+
+/// A part of a string with wildcards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    /// A wildcard with its name, which is `%` for the anonymous wildcard.
+    Wildcard(String),
+}
+
+/// Split `s` into literals and wildcards.  A wildcard is either `%` or a name in braces (e.g.,
+/// `{target}`), where the name starts with a letter or underscore and continues with
+/// alphanumerics and underscores.  Doubled braces (`{{` and `}}`) are literal braces, and braces
+/// that do not enclose such a name are literals as well.
+fn segments(s: &str) -> Vec<Segment> {
+    static NAMED: OnceLock<Regex> = OnceLock::new();
+    let named = NAMED.get_or_init(|| Regex::new(r"^\{([[:alpha:]_][[:alnum:]_]*)\}").unwrap());
+    let mut segments = Vec::new();
+    let mut literal = String::new();
+    let mut rest = s;
+    while let Some(c) = rest.chars().next() {
+        let wildcard = match c {
+            '%' => Some(("%".to_string(), 1)),
+            '{' => named
+                .captures(rest)
+                .map(|caps| (caps[1].to_string(), caps[0].len())),
+            _ => None,
+        };
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            literal.push(c);
+            rest = &rest[2..];
+            continue;
+        }
+        match wildcard {
+            Some((name, len)) => {
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Wildcard(name));
+                rest = &rest[len..];
+            }
+            None => {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if !literal.is_empty() {
+        segments.push(Segment::Literal(literal));
+    }
+    segments
+}
+
+/// Names of the wildcards in `s`, in order of appearance and including repetitions.
+pub fn wildcards(s: &str) -> Vec<String> {
+    segments(s)
+        .into_iter()
+        .filter_map(|seg| match seg {
+            Segment::Wildcard(name) => Some(name),
+            Segment::Literal(_) => None,
+        })
+        .collect()
+}
+
+/// The wildcard with name `name` as it is written in names and paths (e.g., `{target}`).
+pub fn syntax(name: &str) -> String {
+    match name {
+        "%" => name.to_string(),
+        _ => format!("{{{}}}", name),
+    }
+}
+
+/// Escape the braces in the literal `s`, so that it is not parsed as wildcards.
+pub fn escape(s: &str) -> String {
+    s.replace('{', "{{").replace('}', "}}")
+}
+
+/// Substitute the wildcards in `s` that have a value in `bindings`.  Other wildcards are kept, and
+/// so are escaped braces, so the result can be parsed again.
+pub fn substitute(s: &str, bindings: &[(String, String)]) -> String {
+    render(s, bindings, true)
+}
+
+/// Substitute the wildcards in `s` that have a value in `bindings` and unescape braces, which
+/// yields the name or path that `s` stands for.  Other wildcards are kept.
+pub fn instantiate(s: &str, bindings: &[(String, String)]) -> String {
+    render(s, bindings, false)
+}
+
+fn render(s: &str, bindings: &[(String, String)], keep_escapes: bool) -> String {
+    segments(s)
+        .into_iter()
+        .map(|seg| match seg {
+            Segment::Literal(lit) if keep_escapes => escape(&lit),
+            Segment::Literal(lit) => lit,
+            Segment::Wildcard(name) => match bindings.iter().find(|(n, _)| *n == name) {
+                Some((_, value)) if keep_escapes => escape(value),
+                Some((_, value)) => value.clone(),
+                None => syntax(&name),
+            },
+        })
+        .collect()
+}
+
+/// All combinations of the `values` of the given `wildcards`, or `None` if a wildcard has no
+/// values.
+pub fn combinations(
+    wildcards: &[String],
+    values: &BTreeMap<String, Vec<String>>,
+) -> Option<Vec<Bindings>> {
+    let mut combinations = vec![Bindings::new()];
+    for wildcard in wildcards {
+        let wildcard_values = values.get(wildcard)?;
+        combinations = combinations
+            .into_iter()
+            .flat_map(|bindings| {
+                wildcard_values.iter().map(move |value| {
+                    let mut bindings = bindings.clone();
+                    bindings.push((wildcard.clone(), value.clone()));
+                    bindings
+                })
+            })
+            .collect();
+    }
+    Some(combinations)
+}
+
+/// The name of a Pattern Artifact, which contains at least one wildcard.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    regex: Regex,
+    wildcards: Vec<String>,
+    specificity: usize,
+}
+
+impl Pattern {
+    /// Parse the artifact name `name`.  Returns `None` if `name` contains no wildcards or contains
+    /// a wildcard more than once.
+    pub fn parse(name: &str) -> Option<Pattern> {
+        let segments = segments(name);
+        let mut wildcards: Vec<String> = Vec::new();
+        let mut regex = String::from("^");
+        let mut specificity = 0;
+        for seg in &segments {
+            match seg {
+                Segment::Literal(lit) => {
+                    regex.push_str(&regex::escape(lit));
+                    specificity += lit.chars().count();
+                }
+                Segment::Wildcard(name) => {
+                    if wildcards.contains(name) {
+                        return None;
+                    }
+                    wildcards.push(name.clone());
+                    regex.push_str(&format!("({})", VALUE_REGEX));
+                }
+            }
+        }
+        regex.push('$');
+        if wildcards.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            regex: Regex::new(&regex).ok()?,
+            wildcards,
+            specificity,
+        })
+    }
+
+    /// Names of the wildcards, in order of appearance.
+    pub fn wildcards(&self) -> &[String] {
+        &self.wildcards
+    }
+
+    /// Number of literal (i.e., non-wildcard) characters.  When several Patterns match a name, the
+    /// one with the highest specificity is used.
+    pub fn specificity(&self) -> usize {
+        self.specificity
+    }
+
+    /// Match `name` against the Pattern and return the values of the wildcards if it matches.
+    pub fn matches(&self, name: &str) -> Option<Bindings> {
+        self.regex.captures(name).map(|caps| {
+            self.wildcards
+                .iter()
+                .enumerate()
+                .map(|(idx, wildcard)| (wildcard.clone(), caps[idx + 1].to_string()))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use maplit::btreemap;
+
+    fn bindings(pairs: &[(&str, &str)]) -> Bindings {
+        pairs
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_and_match() {
+        let pattern = Pattern::parse("lib-{target}-{config}").unwrap();
+        assert_eq!(pattern.wildcards(), &["target", "config"]);
+        assert_eq!(pattern.specificity(), 5);
+        assert_eq!(
+            pattern.matches("lib-x86-release"),
+            Some(bindings(&[("target", "x86"), ("config", "release")]))
+        );
+        assert_eq!(pattern.matches("lib-x86"), None);
+        assert_eq!(pattern.matches("lib-x86/release"), None);
+        let pattern = Pattern::parse("lib-%").unwrap();
+        assert_eq!(pattern.matches("lib-foo"), Some(bindings(&[("%", "foo")])));
+        assert!(Pattern::parse("lib").is_none());
+        assert!(Pattern::parse("lib-{1}").is_none());
+        assert!(Pattern::parse("lib-{a}-{a}").is_none());
+        let pattern = Pattern::parse("lib{{x}}-%").unwrap();
+        assert_eq!(pattern.specificity(), 7);
+        assert_eq!(
+            pattern.matches("lib{x}-foo"),
+            Some(bindings(&[("%", "foo")]))
+        );
+        assert!(Pattern::parse("lib{{x}}").is_none());
+    }
+
+    #[test]
+    fn substitute_wildcards() {
+        let b = bindings(&[("target", "x86"), ("%", "foo")]);
+        assert_eq!(
+            substitute("out/{target}/{config}/%.so", &b),
+            "out/x86/{config}/foo.so"
+        );
+        assert_eq!(instantiate("a{b c}", &b), "a{b c}");
+        assert_eq!(wildcards("{a}/%/{b}/{a}"), vec!["a", "%", "b", "a"]);
+        // Doubled braces are literal braces.
+        assert!(wildcards("out/{{target}}/{{}}").is_empty());
+        assert_eq!(
+            instantiate("out/{{target}}/{target}", &b),
+            "out/{target}/x86"
+        );
+        assert_eq!(
+            substitute("out/{{target}}/{target}/{config}", &b),
+            "out/{{target}}/x86/{config}"
+        );
+    }
+
+    #[test]
+    fn combine_values() {
+        let values = btreemap! {
+            "target".to_string() => vec!["x86".to_string(), "arm".to_string()],
+            "config".to_string() => vec!["debug".to_string(), "release".to_string()],
+        };
+        let wildcards = vec!["target".to_string(), "config".to_string()];
+        let combinations = combinations(&wildcards, &values).unwrap();
+        assert_eq!(combinations.len(), 4);
+        assert_eq!(
+            combinations[1],
+            bindings(&[("target", "x86"), ("config", "release")])
+        );
+        assert!(super::combinations(&["other".to_string()], &values).is_none());
+    }
+}
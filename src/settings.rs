@@ -0,0 +1,638 @@
+// Copyright 2020 Andreas Kurth
+//
+// SPDX-License-Identifier: (Apache-2.0 OR MIT)
+
+//! User and system settings
+//!
+//! Settings configure how Memora runs on a machine, independently of the artifacts defined in the
+//! Manifest of a repository.  Settings are taken from the following sources, where later sources
+//! take precedence over earlier ones:
+//! 1. defaults,
+//! 2. the Manifest (only `cache_root_dir`, `fallback_cache_dirs`, and `key_mode`),
+//! 3. the system settings file `/etc/memora.yml`,
+//! 4. the user settings file `$XDG_CONFIG_HOME/memora/config.yml` (or
+//!    `~/.config/memora/config.yml` if `XDG_CONFIG_HOME` is not set),
+//! 5. environment variables (`MEMORA_CACHE_ROOT_DIR`, `MEMORA_LOCAL_CACHE_DIR`,
+//!    `MEMORA_WRITE_POLICY`, `MEMORA_MTIMES`, `MEMORA_PRESERVE_OWNERSHIP`,
+//!    `MEMORA_PRESERVE_XATTRS`, `MEMORA_COPY_THREADS`, `MEMORA_KEY_MODE`,
+//!    `MEMORA_CROSS_BRANCH_HITS`, `MEMORA_SELECTION_POLICY`, `MEMORA_SHALLOW_POLICY`,
+//!    `MEMORA_MEMO_MAX_ENTRIES`, `MEMORA_LOCK_TIMEOUT`, and `MEMORA_LOG_LEVEL`).
+
+use crate::cache::{KeyMode, MtimePolicy, SelectionPolicy, ShallowPolicy, Tier, WritePolicy};
+use crate::config::Manifest;
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The source of a setting.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// The built-in default.
+    Default,
+    /// The Manifest at the contained path.
+    Manifest(PathBuf),
+    /// The settings file at the contained path.
+    File(PathBuf),
+    /// The contained environment variable.
+    Env(&'static str),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::Manifest(p) => write!(f, "manifest {:?}", p),
+            Source::File(p) => write!(f, "settings file {:?}", p),
+            Source::Env(var) => write!(f, "environment variable {}", var),
+        }
+    }
+}
+
+/// The value of a setting together with its source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting<T> {
+    pub value: T,
+    pub source: Source,
+}
+
+impl<T> Setting<T> {
+    fn new(value: T, source: Source) -> Setting<T> {
+        Setting { value, source }
+    }
+}
+
+/// Settings for running Memora.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// The root directory of the build artifact cache.
+    ///
+    /// The path can be absolute or relative to the root of the Git repository, regardless of its
+    /// source.
+    pub cache_root_dir: Option<Setting<PathBuf>>,
+    /// Further cache directories that are searched, in order, after the cache root directory.
+    /// Entries are inserted only into the directories that are not read-only.
+    ///
+    /// The paths can be absolute or relative to the root of the Git repository.
+    pub fallback_cache_dirs: Setting<Vec<Tier>>,
+    /// A local cache directory in front of the cache root directory.  If set, entries are looked
+    /// up in this directory first, and entries found only under the cache root directory are
+    /// copied to this directory.
+    ///
+    /// The path can be absolute or relative to the root of the Git repository.
+    pub local_cache_dir: Option<Setting<PathBuf>>,
+    /// When inserted entries are written to the cache root directory if `local_cache_dir` is set.
+    pub write_policy: Setting<WritePolicy>,
+    /// The modification times of inserted and retrieved outputs.
+    pub mtimes: Setting<MtimePolicy>,
+    /// Whether the owner and group of outputs are preserved on insertion and retrieval.
+    pub preserve_ownership: Setting<bool>,
+    /// Whether the extended attributes of outputs are preserved on insertion and retrieval.
+    pub preserve_xattrs: Setting<bool>,
+    /// Number of threads that copy the files of outputs.  If zero, as many threads as the machine
+    /// can run in parallel are used.
+    pub copy_threads: Setting<usize>,
+    /// How the entries of the cache are keyed.
+    pub key_mode: Setting<KeyMode>,
+    /// Whether entries on other branches are used if the inputs are identical.
+    pub cross_branch_hits: Setting<bool>,
+    /// Which entry is used if several entries match an artifact.
+    pub selection_policy: Setting<SelectionPolicy>,
+    /// How artifacts are keyed whose history is truncated by a shallow clone.
+    pub shallow_policy: Setting<ShallowPolicy>,
+    /// Maximum number of answers to Git queries that are kept across runs in the Git directory of
+    /// the repository.  If zero, answers are not kept across runs.
+    pub memo_max_entries: Setting<usize>,
+    /// Maximum duration to wait for the lock of the cache.  If not set, wait indefinitely.
+    pub lock_timeout: Option<Setting<Duration>>,
+    /// Log level (in the filter syntax of `env_logger`).  The `RUST_LOG` environment variable
+    /// takes precedence.
+    pub log_level: Setting<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SerdeSettings {
+    cache_root_dir: Option<PathBuf>,
+    fallback_cache_dirs: Option<Vec<Tier>>,
+    local_cache_dir: Option<PathBuf>,
+    write_policy: Option<String>,
+    mtimes: Option<String>,
+    preserve_ownership: Option<bool>,
+    preserve_xattrs: Option<bool>,
+    copy_threads: Option<usize>,
+    key_mode: Option<String>,
+    cross_branch_hits: Option<bool>,
+    selection_policy: Option<String>,
+    shallow_policy: Option<String>,
+    memo_max_entries: Option<usize>,
+    /// In seconds.
+    lock_timeout: Option<f64>,
+    log_level: Option<String>,
+}
+
+/// Environment variable for `cache_root_dir`.
+pub const ENV_CACHE_ROOT_DIR: &str = "MEMORA_CACHE_ROOT_DIR";
+/// Environment variable for `local_cache_dir`.
+pub const ENV_LOCAL_CACHE_DIR: &str = "MEMORA_LOCAL_CACHE_DIR";
+/// Environment variable for `write_policy`.
+pub const ENV_WRITE_POLICY: &str = "MEMORA_WRITE_POLICY";
+/// Environment variable for `mtimes`.
+pub const ENV_MTIMES: &str = "MEMORA_MTIMES";
+/// Environment variable for `preserve_ownership` (`true` or `false`).
+pub const ENV_PRESERVE_OWNERSHIP: &str = "MEMORA_PRESERVE_OWNERSHIP";
+/// Environment variable for `preserve_xattrs` (`true` or `false`).
+pub const ENV_PRESERVE_XATTRS: &str = "MEMORA_PRESERVE_XATTRS";
+/// Environment variable for `copy_threads`.
+pub const ENV_COPY_THREADS: &str = "MEMORA_COPY_THREADS";
+/// Environment variable for `key_mode`.
+pub const ENV_KEY_MODE: &str = "MEMORA_KEY_MODE";
+/// Environment variable for `cross_branch_hits` (`true` or `false`).
+pub const ENV_CROSS_BRANCH_HITS: &str = "MEMORA_CROSS_BRANCH_HITS";
+/// Environment variable for `selection_policy`.
+pub const ENV_SELECTION_POLICY: &str = "MEMORA_SELECTION_POLICY";
+/// Environment variable for `shallow_policy`.
+pub const ENV_SHALLOW_POLICY: &str = "MEMORA_SHALLOW_POLICY";
+/// Environment variable for `memo_max_entries`.
+pub const ENV_MEMO_MAX_ENTRIES: &str = "MEMORA_MEMO_MAX_ENTRIES";
+/// Environment variable for `lock_timeout` (in seconds).
+pub const ENV_LOCK_TIMEOUT: &str = "MEMORA_LOCK_TIMEOUT";
+/// Environment variable for `log_level`.
+pub const ENV_LOG_LEVEL: &str = "MEMORA_LOG_LEVEL";
+
+fn write_policy(policy: &str, source: &Source) -> Result<WritePolicy> {
+    policy
+        .parse()
+        .map_err(|cause| Error::chain(format!("Invalid write policy in {}:", source), cause))
+}
+
+fn mtimes(policy: &str, source: &Source) -> Result<MtimePolicy> {
+    policy.parse().map_err(|cause| {
+        Error::chain(
+            format!("Invalid modification time policy in {}:", source),
+            cause,
+        )
+    })
+}
+
+fn key_mode(mode: &str, source: &Source) -> Result<KeyMode> {
+    mode.parse()
+        .map_err(|cause| Error::chain(format!("Invalid key mode in {}:", source), cause))
+}
+
+fn selection_policy(policy: &str, source: &Source) -> Result<SelectionPolicy> {
+    policy
+        .parse()
+        .map_err(|cause| Error::chain(format!("Invalid selection policy in {}:", source), cause))
+}
+
+fn shallow_policy(policy: &str, source: &Source) -> Result<ShallowPolicy> {
+    policy.parse().map_err(|cause| {
+        Error::chain(
+            format!("Invalid shallow clone policy in {}:", source),
+            cause,
+        )
+    })
+}
+
+fn flag(value: &str, source: &Source) -> Result<bool> {
+    value.parse().map_err(|cause| {
+        Error::chain(
+            format!(
+                "Invalid value {:?} in {} (expected true or false)!",
+                value, source
+            ),
+            cause,
+        )
+    })
+}
+
+fn lock_timeout(secs: f64, source: &Source) -> Result<Duration> {
+    if secs.is_finite() && secs >= 0.0 {
+        Ok(Duration::from_secs_f64(secs))
+    } else {
+        Error::result(format!("Invalid lock timeout {} in {}!", secs, source))
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            cache_root_dir: None,
+            fallback_cache_dirs: Setting::new(vec![], Source::Default),
+            local_cache_dir: None,
+            write_policy: Setting::new(WritePolicy::default(), Source::Default),
+            mtimes: Setting::new(MtimePolicy::default(), Source::Default),
+            preserve_ownership: Setting::new(false, Source::Default),
+            preserve_xattrs: Setting::new(false, Source::Default),
+            copy_threads: Setting::new(1, Source::Default),
+            key_mode: Setting::new(KeyMode::default(), Source::Default),
+            cross_branch_hits: Setting::new(false, Source::Default),
+            selection_policy: Setting::new(SelectionPolicy::default(), Source::Default),
+            shallow_policy: Setting::new(ShallowPolicy::default(), Source::Default),
+            memo_max_entries: Setting::new(50_000, Source::Default),
+            lock_timeout: None,
+            log_level: Setting::new("info".to_string(), Source::Default),
+        }
+    }
+}
+
+impl Settings {
+    /// Paths of the system and the user settings file, in order of increasing precedence.
+    pub fn default_paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from("/etc/memora.yml")];
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        if let Some(config_home) = config_home {
+            paths.push(config_home.join("memora").join("config.yml"));
+        }
+        paths
+    }
+
+    /// Load settings from the settings files at `paths` (in order of increasing precedence) and
+    /// from the environment variables returned by `env`.  Settings files that do not exist are
+    /// skipped.
+    pub fn load<F>(paths: &[PathBuf], env: F) -> Result<Settings>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut settings = Settings::default();
+        for path in paths.iter().filter(|p| p.is_file()) {
+            settings.apply_file(path)?;
+        }
+        settings.apply_env(env)?;
+        Ok(settings)
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<()> {
+        let contents = std::fs::read_to_string(path).map_err(|cause| {
+            Error::chain(format!("Cannot open settings file {:?}!", path), cause)
+        })?;
+        let file: SerdeSettings = serde_yaml::from_str(&contents).map_err(|cause| {
+            Error::chain(format!("Syntax error in settings file {:?}!", path), cause)
+        })?;
+        let source = Source::File(path.to_path_buf());
+        if let Some(dir) = file.cache_root_dir {
+            self.cache_root_dir = Some(Setting::new(dir, source.clone()));
+        }
+        if let Some(dirs) = file.fallback_cache_dirs {
+            self.fallback_cache_dirs = Setting::new(dirs, source.clone());
+        }
+        if let Some(dir) = file.local_cache_dir {
+            self.local_cache_dir = Some(Setting::new(dir, source.clone()));
+        }
+        if let Some(policy) = file.write_policy {
+            self.write_policy = Setting::new(write_policy(&policy, &source)?, source.clone());
+        }
+        if let Some(policy) = file.mtimes {
+            self.mtimes = Setting::new(mtimes(&policy, &source)?, source.clone());
+        }
+        if let Some(preserve) = file.preserve_ownership {
+            self.preserve_ownership = Setting::new(preserve, source.clone());
+        }
+        if let Some(preserve) = file.preserve_xattrs {
+            self.preserve_xattrs = Setting::new(preserve, source.clone());
+        }
+        if let Some(threads) = file.copy_threads {
+            self.copy_threads = Setting::new(threads, source.clone());
+        }
+        if let Some(mode) = file.key_mode {
+            self.key_mode = Setting::new(key_mode(&mode, &source)?, source.clone());
+        }
+        if let Some(cross_branch) = file.cross_branch_hits {
+            self.cross_branch_hits = Setting::new(cross_branch, source.clone());
+        }
+        if let Some(policy) = file.selection_policy {
+            self.selection_policy =
+                Setting::new(selection_policy(&policy, &source)?, source.clone());
+        }
+        if let Some(policy) = file.shallow_policy {
+            self.shallow_policy = Setting::new(shallow_policy(&policy, &source)?, source.clone());
+        }
+        if let Some(max_entries) = file.memo_max_entries {
+            self.memo_max_entries = Setting::new(max_entries, source.clone());
+        }
+        if let Some(secs) = file.lock_timeout {
+            let timeout = lock_timeout(secs, &source)?;
+            self.lock_timeout = Some(Setting::new(timeout, source.clone()));
+        }
+        if let Some(level) = file.log_level {
+            self.log_level = Setting::new(level, source);
+        }
+        Ok(())
+    }
+
+    fn apply_env<F>(&mut self, env: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(dir) = env(ENV_CACHE_ROOT_DIR) {
+            self.cache_root_dir = Some(Setting::new(
+                PathBuf::from(dir),
+                Source::Env(ENV_CACHE_ROOT_DIR),
+            ));
+        }
+        if let Some(dir) = env(ENV_LOCAL_CACHE_DIR) {
+            self.local_cache_dir = Some(Setting::new(
+                PathBuf::from(dir),
+                Source::Env(ENV_LOCAL_CACHE_DIR),
+            ));
+        }
+        if let Some(policy) = env(ENV_WRITE_POLICY) {
+            let source = Source::Env(ENV_WRITE_POLICY);
+            self.write_policy = Setting::new(write_policy(&policy, &source)?, source);
+        }
+        if let Some(policy) = env(ENV_MTIMES) {
+            let source = Source::Env(ENV_MTIMES);
+            self.mtimes = Setting::new(mtimes(&policy, &source)?, source);
+        }
+        if let Some(preserve) = env(ENV_PRESERVE_OWNERSHIP) {
+            let source = Source::Env(ENV_PRESERVE_OWNERSHIP);
+            self.preserve_ownership = Setting::new(flag(&preserve, &source)?, source);
+        }
+        if let Some(preserve) = env(ENV_PRESERVE_XATTRS) {
+            let source = Source::Env(ENV_PRESERVE_XATTRS);
+            self.preserve_xattrs = Setting::new(flag(&preserve, &source)?, source);
+        }
+        if let Some(threads) = env(ENV_COPY_THREADS) {
+            let source = Source::Env(ENV_COPY_THREADS);
+            let threads = threads.parse().map_err(|cause| {
+                Error::chain(
+                    format!("Invalid number of threads {:?} in {}!", threads, source),
+                    cause,
+                )
+            })?;
+            self.copy_threads = Setting::new(threads, source);
+        }
+        if let Some(mode) = env(ENV_KEY_MODE) {
+            let source = Source::Env(ENV_KEY_MODE);
+            self.key_mode = Setting::new(key_mode(&mode, &source)?, source);
+        }
+        if let Some(cross_branch) = env(ENV_CROSS_BRANCH_HITS) {
+            let source = Source::Env(ENV_CROSS_BRANCH_HITS);
+            self.cross_branch_hits = Setting::new(flag(&cross_branch, &source)?, source);
+        }
+        if let Some(policy) = env(ENV_SELECTION_POLICY) {
+            let source = Source::Env(ENV_SELECTION_POLICY);
+            self.selection_policy = Setting::new(selection_policy(&policy, &source)?, source);
+        }
+        if let Some(policy) = env(ENV_SHALLOW_POLICY) {
+            let source = Source::Env(ENV_SHALLOW_POLICY);
+            self.shallow_policy = Setting::new(shallow_policy(&policy, &source)?, source);
+        }
+        if let Some(max_entries) = env(ENV_MEMO_MAX_ENTRIES) {
+            let source = Source::Env(ENV_MEMO_MAX_ENTRIES);
+            let max_entries = max_entries.parse().map_err(|cause| {
+                Error::chain(
+                    format!("Invalid number of entries {:?} in {}!", max_entries, source),
+                    cause,
+                )
+            })?;
+            self.memo_max_entries = Setting::new(max_entries, source);
+        }
+        if let Some(secs) = env(ENV_LOCK_TIMEOUT) {
+            let source = Source::Env(ENV_LOCK_TIMEOUT);
+            let secs: f64 = secs.parse().map_err(|cause| {
+                Error::chain(
+                    format!("Invalid lock timeout {:?} in {}!", secs, source),
+                    cause,
+                )
+            })?;
+            self.lock_timeout = Some(Setting::new(lock_timeout(secs, &source)?, source));
+        }
+        if let Some(level) = env(ENV_LOG_LEVEL) {
+            self.log_level = Setting::new(level, Source::Env(ENV_LOG_LEVEL));
+        }
+        Ok(())
+    }
+
+    /// Supply the settings that are not set from the Manifest at `path`, which has the lowest
+    /// precedence of all settings sources except the defaults.
+    pub fn supply_from_manifest(&mut self, manifest: &Manifest, path: &Path) {
+        if self.cache_root_dir.is_none() {
+            self.cache_root_dir = manifest
+                .cache_root_dir
+                .clone()
+                .map(|dir| Setting::new(dir, Source::Manifest(path.to_path_buf())));
+        }
+        if self.fallback_cache_dirs.source == Source::Default {
+            self.fallback_cache_dirs = Setting::new(
+                manifest.fallback_cache_dirs.clone(),
+                Source::Manifest(path.to_path_buf()),
+            );
+        }
+        if let (Source::Default, Some(mode)) = (&self.key_mode.source, manifest.key_mode) {
+            self.key_mode = Setting::new(mode, Source::Manifest(path.to_path_buf()));
+        }
+    }
+}
+
+impl Display for Settings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.cache_root_dir {
+            Some(s) => writeln!(f, "cache_root_dir: {:?} (from {})", s.value, s.source)?,
+            None => writeln!(f, "cache_root_dir: not set")?,
+        }
+        writeln!(
+            f,
+            "fallback_cache_dirs: [{}] (from {})",
+            self.fallback_cache_dirs
+                .value
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.fallback_cache_dirs.source
+        )?;
+        match &self.local_cache_dir {
+            Some(s) => writeln!(f, "local_cache_dir: {:?} (from {})", s.value, s.source)?,
+            None => writeln!(f, "local_cache_dir: not set")?,
+        }
+        writeln!(
+            f,
+            "write_policy: {} (from {})",
+            self.write_policy.value, self.write_policy.source
+        )?;
+        writeln!(
+            f,
+            "mtimes: {} (from {})",
+            self.mtimes.value, self.mtimes.source
+        )?;
+        writeln!(
+            f,
+            "preserve_ownership: {} (from {})",
+            self.preserve_ownership.value, self.preserve_ownership.source
+        )?;
+        writeln!(
+            f,
+            "preserve_xattrs: {} (from {})",
+            self.preserve_xattrs.value, self.preserve_xattrs.source
+        )?;
+        writeln!(
+            f,
+            "copy_threads: {} (from {})",
+            self.copy_threads.value, self.copy_threads.source
+        )?;
+        writeln!(
+            f,
+            "key_mode: {} (from {})",
+            self.key_mode.value, self.key_mode.source
+        )?;
+        writeln!(
+            f,
+            "cross_branch_hits: {} (from {})",
+            self.cross_branch_hits.value, self.cross_branch_hits.source
+        )?;
+        writeln!(
+            f,
+            "selection_policy: {} (from {})",
+            self.selection_policy.value, self.selection_policy.source
+        )?;
+        writeln!(
+            f,
+            "shallow_policy: {} (from {})",
+            self.shallow_policy.value, self.shallow_policy.source
+        )?;
+        writeln!(
+            f,
+            "memo_max_entries: {} (from {})",
+            self.memo_max_entries.value, self.memo_max_entries.source
+        )?;
+        match &self.lock_timeout {
+            Some(s) => writeln!(f, "lock_timeout: {:?} (from {})", s.value, s.source)?,
+            None => writeln!(f, "lock_timeout: none (from {})", Source::Default)?,
+        }
+        write!(
+            f,
+            "log_level: {:?} (from {})",
+            self.log_level.value, self.log_level.source
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{create_file, write_file};
+    use std::collections::HashMap;
+    use tempdir::TempDir;
+
+    fn setup(files: &[&str]) -> Result<(TempDir, Vec<PathBuf>)> {
+        let tmp = TempDir::new("memora-test-settings")
+            .map_err(|cause| Error::chain("Could not create temporary directory:", cause))?;
+        let mut paths = Vec::new();
+        for (idx, source) in files.iter().enumerate() {
+            let path = tmp.path().join(format!("{}.yml", idx));
+            let mut file = create_file(&path)?;
+            write_file(&mut file, source)?;
+            paths.push(path);
+        }
+        Ok((tmp, paths))
+    }
+
+    #[test]
+    fn precedence() -> Result<()> {
+        let (_tmp, mut paths) = setup(&[
+            "cache_root_dir: /system\nlock_timeout: 10\nlog_level: warn\n\
+             fallback_cache_dirs:\n  - path: /release\n    read_only: true\n",
+            "cache_root_dir: /user\nwrite_policy: write-back\nselection_policy: verified\n",
+        ])?;
+        paths.push(PathBuf::from("/nonexistent/memora.yml"));
+        let env: HashMap<&str, &str> = vec![
+            (ENV_LOCK_TIMEOUT, "2.5"),
+            (ENV_MTIMES, "preserve"),
+            (ENV_COPY_THREADS, "8"),
+            (ENV_KEY_MODE, "tree-hash"),
+            (ENV_CROSS_BRANCH_HITS, "true"),
+            (ENV_MEMO_MAX_ENTRIES, "0"),
+            (ENV_SHALLOW_POLICY, "deepen"),
+        ]
+        .into_iter()
+        .collect();
+        let settings = Settings::load(&paths, |var| env.get(var).map(|s| s.to_string()))?;
+        assert_eq!(
+            settings.cache_root_dir,
+            Some(Setting::new(
+                PathBuf::from("/user"),
+                Source::File(paths[1].clone())
+            ))
+        );
+        assert_eq!(
+            settings.lock_timeout,
+            Some(Setting::new(
+                Duration::from_millis(2500),
+                Source::Env(ENV_LOCK_TIMEOUT)
+            ))
+        );
+        assert_eq!(
+            settings.fallback_cache_dirs,
+            Setting::new(
+                vec![Tier {
+                    path: PathBuf::from("/release"),
+                    read_only: true
+                }],
+                Source::File(paths[0].clone())
+            )
+        );
+        assert_eq!(
+            settings.mtimes,
+            Setting::new(MtimePolicy::Preserve, Source::Env(ENV_MTIMES))
+        );
+        assert_eq!(
+            settings.copy_threads,
+            Setting::new(8, Source::Env(ENV_COPY_THREADS))
+        );
+        assert_eq!(
+            settings.key_mode,
+            Setting::new(KeyMode::TreeHash, Source::Env(ENV_KEY_MODE))
+        );
+        assert_eq!(
+            settings.cross_branch_hits,
+            Setting::new(true, Source::Env(ENV_CROSS_BRANCH_HITS))
+        );
+        assert_eq!(
+            settings.selection_policy,
+            Setting::new(SelectionPolicy::Verified, Source::File(paths[1].clone()))
+        );
+        assert_eq!(
+            settings.shallow_policy,
+            Setting::new(ShallowPolicy::Deepen, Source::Env(ENV_SHALLOW_POLICY))
+        );
+        assert_eq!(
+            settings.memo_max_entries,
+            Setting::new(0, Source::Env(ENV_MEMO_MAX_ENTRIES))
+        );
+        assert_eq!(
+            settings.write_policy,
+            Setting::new(WritePolicy::WriteBack, Source::File(paths[1].clone()))
+        );
+        assert_eq!(
+            settings.log_level,
+            Setting::new("warn".to_string(), Source::File(paths[0].clone()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_settings() -> Result<()> {
+        let (_tmp, paths) = setup(&["cache_dir: /x\n"])?;
+        assert!(Settings::load(&paths, |_| None).is_err());
+        let (_tmp, paths) = setup(&["lock_timeout: -1\n"])?;
+        assert!(Settings::load(&paths, |_| None).is_err());
+        let (_tmp, paths) = setup(&["write_policy: write-around\n"])?;
+        assert!(Settings::load(&paths, |_| None).is_err());
+        assert!(Settings::load(&[], |_| Some("soon".to_string())).is_err());
+        let (_tmp, paths) = setup(&["mtimes: keep\n"])?;
+        assert!(Settings::load(&paths, |_| None).is_err());
+        let (_tmp, paths) = setup(&["selection_policy: random\n"])?;
+        assert!(Settings::load(&paths, |_| None).is_err());
+        let (_tmp, paths) = setup(&["shallow_policy: unshallow\n"])?;
+        assert!(Settings::load(&paths, |_| None).is_err());
+        let (_tmp, paths) = setup(&["key_mode: content\n"])?;
+        assert!(Settings::load(&paths, |_| None).is_err());
+        Ok(())
+    }
+}
@@ -4,6 +4,27 @@
 
 //! Various utilities
 
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Lock `mutex`, even if another thread panicked while holding it.  The mutexes in Memora protect
+/// caches, which stay consistent across panics.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Return a suffix for temporary files that is unique across processes and across the calls in a
+/// process (e.g., `tmp.1234.0`), so concurrent writers never share a temporary file.
+pub fn tmp_suffix() -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "tmp.{}.{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
 // CC BY-SA 4.0 Sven Marnach
 // Adapted from https://stackoverflow.com/a/55041833.
 pub fn trim_newline(mut s: String) -> String {
@@ -15,3 +36,68 @@ pub fn trim_newline(mut s: String) -> String {
     }
     s
 }
+
+/// Return `path` without `.` components, so that paths can be compared component-wise.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .collect()
+}
+
+/// Return `path` with `.` and `..` components resolved lexically (i.e., without following
+/// symlinks).  A `..` at the root of an absolute path is dropped, and `..` components at the start
+/// of a relative path are kept.
+pub fn resolve_path(path: &Path) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match resolved.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    resolved.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+                _ => resolved.push(component),
+            },
+            _ => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+/// Return the relative path from directory `base` to `target`, where both are absolute and
+/// resolved (see [`resolve_path`](fn.resolve_path.html)).
+pub fn relative_path(target: &Path, base: &Path) -> PathBuf {
+    let common = target
+        .components()
+        .zip(base.components())
+        .take_while(|(t, b)| t == b)
+        .count();
+    let mut relative: PathBuf = base.components().skip(common).map(|_| "..").collect();
+    relative.extend(target.components().skip(common));
+    relative
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_paths() {
+        assert_eq!(resolve_path(Path::new("a/./b/../c")), Path::new("a/c"));
+        assert_eq!(resolve_path(Path::new("a/../../b")), Path::new("../b"));
+        assert_eq!(resolve_path(Path::new("/a/../../b")), Path::new("/b"));
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(
+            relative_path(Path::new("/repo/lib/a.so"), Path::new("/repo/out/bin")),
+            Path::new("../../lib/a.so")
+        );
+        assert_eq!(
+            relative_path(Path::new("/repo/out/bin/a"), Path::new("/repo/out")),
+            Path::new("bin/a")
+        );
+    }
+}